@@ -0,0 +1,105 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use winapi::shared::windef::{HACCEL, HWND};
+use winapi::um::winuser::{
+    ACCEL, CreateAcceleratorTableW, DestroyAcceleratorTable, FALT, FCONTROL, FSHIFT, FVIRTKEY,
+    GA_ROOT, GetAncestor, MSG, TranslateAcceleratorW,
+};
+
+use Error;
+use shortcut::Shortcut;
+
+// The message loop has to know which accelerators apply to the window a message is headed for,
+// but it can't safely look at the state of windows it doesn't know about, so windows register
+// their table here instead.
+thread_local!{
+    static ACCELERATORS: RefCell<HashMap<usize, HACCEL>> = RefCell::new(HashMap::new());
+}
+pub(crate) fn register(hwnd: HWND, table: Option<&AcceleratorTable>) {
+    ACCELERATORS.with(|map| {
+        let mut map = map.borrow_mut();
+        match table {
+            Some(table) => map.insert(hwnd as usize, table.as_raw()),
+            None => map.remove(&(hwnd as usize)),
+        };
+    });
+}
+// Returns true if the message was translated into a command and must not be dispatched.
+pub(crate) fn translate(msg: &MSG) -> bool {
+    if msg.hwnd.is_null() {
+        return false;
+    }
+    let root = unsafe { GetAncestor(msg.hwnd, GA_ROOT) };
+    let accel = ACCELERATORS.with(|map| map.borrow().get(&(root as usize)).cloned());
+    match accel {
+        Some(accel) => unsafe {
+            TranslateAcceleratorW(root, accel, msg as *const MSG as *mut MSG) != 0
+        },
+        None => false,
+    }
+}
+pub struct AcceleratorTable {
+    handle: HACCEL,
+    entries: Vec<(Shortcut, u16)>,
+}
+impl AcceleratorTable {
+    pub fn as_raw(&self) -> HACCEL {
+        self.handle
+    }
+    pub fn entries(&self) -> &[(Shortcut, u16)] {
+        &self.entries
+    }
+    // The first shortcut bound to the command, which is the one shown in menus.
+    pub fn shortcut_for(&self, id: u16) -> Option<Shortcut> {
+        self.entries.iter().find(|&&(_, cmd)| cmd == id).map(|&(shortcut, _)| shortcut)
+    }
+}
+impl Drop for AcceleratorTable {
+    fn drop(&mut self) {
+        if unsafe { DestroyAcceleratorTable(self.handle) } == 0 {
+            Error::get_last_error().die("Failed to destroy accelerator table");
+        }
+    }
+}
+pub struct AcceleratorTableBuilder {
+    entries: Vec<(Shortcut, u16)>,
+}
+impl Default for AcceleratorTableBuilder {
+    fn default() -> AcceleratorTableBuilder {
+        AcceleratorTableBuilder::new()
+    }
+}
+impl AcceleratorTableBuilder {
+    pub fn new() -> AcceleratorTableBuilder {
+        AcceleratorTableBuilder {
+            entries: Vec::new(),
+        }
+    }
+    // Pressing the shortcut sends the same `Event::MenuCommand` as choosing a menu item with the
+    // command id, so it also runs any callback attached to that item.
+    pub fn add(mut self, shortcut: Shortcut, id: u16) -> AcceleratorTableBuilder {
+        self.entries.push((shortcut, id));
+        self
+    }
+    pub fn create(self) -> Result<AcceleratorTable, Error> {
+        let mut accels: Vec<ACCEL> = self.entries.iter().map(|&(shortcut, id)| {
+            let mut flags = FVIRTKEY;
+            if shortcut.ctrl { flags |= FCONTROL }
+            if shortcut.alt { flags |= FALT }
+            if shortcut.shift { flags |= FSHIFT }
+            ACCEL {
+                fVirt: flags,
+                key: shortcut.key,
+                cmd: id,
+            }
+        }).collect();
+        let handle = unsafe { CreateAcceleratorTableW(accels.as_mut_ptr(), accels.len() as i32) };
+        if handle.is_null() {
+            return Err(Error::get_last_error());
+        }
+        Ok(AcceleratorTable {
+            handle: handle,
+            entries: self.entries,
+        })
+    }
+}
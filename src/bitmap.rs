@@ -0,0 +1,169 @@
+use std::mem::{forget, size_of, zeroed};
+use std::ptr::{copy_nonoverlapping, null_mut};
+use winapi::ctypes::c_void;
+use winapi::shared::windef::{HBITMAP, HGDIOBJ, HICON};
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+use winapi::um::wingdi::{
+    BITMAP, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, CreateBitmap, CreateDIBSection, DIB_RGB_COLORS,
+    DeleteObject, GetDIBits, GetObjectW,
+};
+use winapi::um::winnt::HANDLE;
+use winapi::um::winuser::{
+    CopyImage, DestroyIcon, GetDC, GetIconInfo, ICONINFO, IMAGE_ICON, ReleaseDC,
+};
+
+use Error;
+use icon::Icon;
+use pixel::{self, mask_stride};
+
+pub struct Bitmap(HBITMAP);
+impl Bitmap {
+    pub fn as_raw(&self) -> HBITMAP {
+        self.0
+    }
+    pub fn into_raw(self) -> HBITMAP {
+        let x = self.0;
+        forget(self);
+        x
+    }
+    // Pixels are top-down rows of BGRA, copied as they are. Menus and AlphaBlend expect the color
+    // to be multiplied by alpha already, see `pixel::premultiply_alpha`, while icons expect it not
+    // to be. An empty size or the wrong number of pixels is an invalid parameter.
+    pub fn from_bgra(width: u32, height: u32, pixels: &[u8]) -> Result<Bitmap, Error> {
+        check_pixels(width, height, pixels)?;
+        let info = dib_info(width as i32, height as i32);
+        let mut bits: *mut c_void = null_mut();
+        let bitmap = unsafe { CreateDIBSection(
+            null_mut(), &info, DIB_RGB_COLORS, &mut bits, null_mut(), 0,
+        )};
+        if bitmap.is_null() {
+            return Err(Error::get_last_error());
+        }
+        unsafe { copy_nonoverlapping(pixels.as_ptr(), bits as *mut u8, pixels.len()) };
+        Ok(Bitmap(bitmap))
+    }
+    // One bit per pixel, with every row padded to 16 bits.
+    pub(crate) fn monochrome(width: u32, height: u32, bits: &[u8]) -> Result<Bitmap, Error> {
+        // CreateBitmap reads as many bytes as the size calls for, however many there are.
+        if mask_stride(width).checked_mul(height as usize) != Some(bits.len()) {
+            return Err(Error::from_raw(ERROR_INVALID_PARAMETER));
+        }
+        let bitmap = unsafe {
+            CreateBitmap(width as i32, height as i32, 1, 1, bits.as_ptr() as *const c_void)
+        };
+        if bitmap.is_null() {
+            return Err(Error::get_last_error());
+        }
+        Ok(Bitmap(bitmap))
+    }
+    // Renders the icon at the given size into a 32-bit bitmap with proper alpha. Icons without an
+    // alpha channel get one generated from their mask.
+    pub fn from_icon(icon: &Icon, width: u32, height: u32) -> Result<Bitmap, Error> {
+        unsafe {
+            let copy = CopyImage(
+                icon.as_raw() as HANDLE, IMAGE_ICON, width as i32, height as i32, 0,
+            ) as HICON;
+            if copy.is_null() {
+                return Err(Error::get_last_error());
+            }
+            let pixels = icon_pixels(copy, width, height);
+            DestroyIcon(copy);
+            let mut pixels = pixels?;
+            pixel::premultiply_alpha(&mut pixels);
+            Bitmap::from_bgra(width, height, &pixels)
+        }
+    }
+}
+impl Drop for Bitmap {
+    fn drop(&mut self) {
+        if unsafe { DeleteObject(self.0 as HGDIOBJ) } == 0 {
+            Error::get_last_error().die("Failed to destroy bitmap");
+        }
+    }
+}
+// Checks that there are four bytes for every pixel of a size Windows can create bitmaps of.
+pub(crate) fn check_pixels(width: u32, height: u32, pixels: &[u8]) -> Result<(), Error> {
+    let valid = width > 0 && height > 0 && width <= i32::MAX as u32
+        && height <= i32::MAX as u32
+        && (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4))
+            == Some(pixels.len());
+    if !valid {
+        return Err(Error::from_raw(ERROR_INVALID_PARAMETER));
+    }
+    Ok(())
+}
+fn dib_info(width: i32, height: i32) -> BITMAPINFO {
+    let mut info: BITMAPINFO = unsafe { zeroed() };
+    info.bmiHeader.biSize = size_of::<BITMAPINFOHEADER>() as u32;
+    info.bmiHeader.biWidth = width;
+    // Negative height means the rows are top-down.
+    info.bmiHeader.biHeight = -height;
+    info.bmiHeader.biPlanes = 1;
+    info.bmiHeader.biBitCount = 32;
+    info.bmiHeader.biCompression = BI_RGB;
+    info
+}
+// The size of the icon, taken from its color bitmap, or from its mask for monochrome icons.
+pub(crate) unsafe fn icon_size(icon: HICON) -> Result<(u32, u32), Error> {
+    let mut info: ICONINFO = zeroed();
+    if GetIconInfo(icon, &mut info) == 0 {
+        return Err(Error::get_last_error());
+    }
+    let color = !info.hbmColor.is_null();
+    let mut bitmap: BITMAP = zeroed();
+    let result = if GetObjectW(
+        if color { info.hbmColor } else { info.hbmMask } as HGDIOBJ,
+        size_of::<BITMAP>() as i32, &mut bitmap as *mut BITMAP as *mut c_void,
+    ) == 0 {
+        Err(Error::get_last_error())
+    } else if color {
+        Ok((bitmap.bmWidth as u32, bitmap.bmHeight as u32))
+    } else {
+        // The mask holds the AND mask above the XOR mask.
+        Ok((bitmap.bmWidth as u32, bitmap.bmHeight as u32 / 2))
+    };
+    if color {
+        DeleteObject(info.hbmColor as HGDIOBJ);
+    }
+    DeleteObject(info.hbmMask as HGDIOBJ);
+    result
+}
+pub(crate) unsafe fn icon_pixels(icon: HICON, width: u32, height: u32) -> Result<Vec<u8>, Error> {
+    let mut info: ICONINFO = zeroed();
+    if GetIconInfo(icon, &mut info) == 0 {
+        return Err(Error::get_last_error());
+    }
+    // Monochrome icons only have a mask of twice the height, and aren't worth supporting.
+    let result = if info.hbmColor.is_null() {
+        Err(Error::from_raw(ERROR_INVALID_PARAMETER))
+    } else {
+        bitmap_pixels(info.hbmColor, width, height).and_then(|mut pixels| {
+            if !pixel::has_alpha(&pixels) {
+                let mask = bitmap_pixels(info.hbmMask, width, height)?;
+                pixel::alpha_from_mask(&mut pixels, &mask);
+            }
+            Ok(pixels)
+        })
+    };
+    if !info.hbmColor.is_null() {
+        DeleteObject(info.hbmColor as HGDIOBJ);
+    }
+    DeleteObject(info.hbmMask as HGDIOBJ);
+    result
+}
+unsafe fn bitmap_pixels(bitmap: HBITMAP, width: u32, height: u32) -> Result<Vec<u8>, Error> {
+    let mut info = dib_info(width as i32, height as i32);
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    let dc = GetDC(null_mut());
+    if dc.is_null() {
+        return Err(Error::get_last_error());
+    }
+    let lines = GetDIBits(
+        dc, bitmap, 0, height, pixels.as_mut_ptr() as *mut c_void, &mut info, DIB_RGB_COLORS,
+    );
+    ReleaseDC(null_mut(), dc);
+    if lines == 0 {
+        return Err(Error::get_last_error());
+    }
+    Ok(pixels)
+}
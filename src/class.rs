@@ -3,19 +3,20 @@ use std::mem::{size_of};
 use std::ptr::null_mut;
 use std::sync::Arc;
 use winapi::shared::minwindef::{ATOM};
-use winapi::shared::windef::HICON;
 use winapi::um::winnt::LPCWSTR;
 use winapi::um::winuser::{RegisterClassExW, WNDCLASSEXW, UnregisterClassW};
 
 use Error;
 use brush::Brush;
+use icon::{Icon, IconPair};
 use wide::ToWide;
 use wndproc::wndproc;
 
 pub struct ClassBuilder {
     name: Vec<u16>,
     background: Option<Brush>,
-    icon: Option<HICON>,
+    icon: Option<Icon>,
+    small_icon: Option<Icon>,
 }
 impl ClassBuilder {
     pub fn new() -> ClassBuilder {
@@ -23,6 +24,7 @@ impl ClassBuilder {
             name: Vec::new(),
             background: None,
             icon: None,
+            small_icon: None,
         }
     }
     pub fn name<T>(mut self, name: &T) -> ClassBuilder where T: ToWide + ?Sized {
@@ -33,10 +35,19 @@ impl ClassBuilder {
         self.background = Some(background);
         self
     }
-    pub fn icon(mut self, icon: HICON) -> ClassBuilder {
+    // The class keeps the icon alive for as long as it is registered. Without a small icon the
+    // system makes one by shrinking this one.
+    pub fn icon(mut self, icon: Icon) -> ClassBuilder {
         self.icon = Some(icon);
         self
     }
+    pub fn small_icon(mut self, icon: Icon) -> ClassBuilder {
+        self.small_icon = Some(icon);
+        self
+    }
+    pub fn icons(self, icons: IconPair) -> ClassBuilder {
+        self.icon(icons.large).small_icon(icons.small)
+    }
     pub fn register(self) -> Result<Class, Error> {
         assert!(!self.name.is_empty());
         let class = WNDCLASSEXW {
@@ -46,34 +57,47 @@ impl ClassBuilder {
             cbClsExtra: 0,
             cbWndExtra: size_of::<usize>() as i32,
             hInstance: null_mut(),
-            hIcon: self.icon.unwrap_or(null_mut()),
+            hIcon: self.icon.as_ref().map(|i| i.as_raw()).unwrap_or(null_mut()),
             hCursor: null_mut(),
             hbrBackground: self.background.map(|b| b.into_raw()).unwrap_or(null_mut()),
             lpszMenuName: null_mut(),
             lpszClassName: self.name.as_ptr(),
-            hIconSm: null_mut(),
+            hIconSm: self.small_icon.as_ref().map(|i| i.as_raw()).unwrap_or(null_mut()),
         };
         let atom = unsafe { RegisterClassExW(&class) };
         if atom == 0 {
             return Err(Error::get_last_error());
         }
-        let class = Class(Arc::new(atom));
+        let class = Class(Arc::new(ClassInternal {
+            atom: atom,
+            _icon: self.icon,
+            _small_icon: self.small_icon,
+        }));
         Ok(class)
     }
 }
+// The icons are dropped after the class is unregistered, which is when nothing can use them.
+struct ClassInternal {
+    atom: ATOM,
+    _icon: Option<Icon>,
+    _small_icon: Option<Icon>,
+}
+// Icon handles aren't tied to a thread, so the class can still be shared between them.
+unsafe impl Send for ClassInternal {}
+unsafe impl Sync for ClassInternal {}
 #[derive(Clone)]
-pub struct Class(Arc<ATOM>);
+pub struct Class(Arc<ClassInternal>);
 impl Class {
     pub fn as_raw(&self) -> ATOM {
-        *self.0
+        self.0.atom
     }
     pub fn as_wstr(&self) -> LPCWSTR {
         self.as_raw() as usize as LPCWSTR
     }
 }
-impl Drop for Class {
+impl Drop for ClassInternal {
     fn drop(&mut self) {
-        if unsafe { UnregisterClassW(self.as_raw() as LPCWSTR, null_mut()) } == 0 {
+        if unsafe { UnregisterClassW(self.atom as usize as LPCWSTR, null_mut()) } == 0 {
             Error::get_last_error().die("Failed to unregister class");
         }
     }
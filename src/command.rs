@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use winapi::shared::winerror::ERROR_NO_MORE_ITEMS;
+
+use Error;
+use window::Window;
+
+// Menu command ids handed out automatically are taken from the upper half of the id space, and
+// menus refuse ids chosen by hand from there so that the two never collide. Menus and the windows
+// they are displayed on are tied to the thread that created them, so a table per thread guarantees
+// that the ids are unique within every window.
+const FIRST_ID: u16 = 0x8000;
+const LAST_ID: u16 = 0xFFFE;
+
+pub(crate) type Command = Rc<RefCell<Box<dyn FnMut(&Window)>>>;
+
+struct CommandTable {
+    next: u16,
+    commands: HashMap<u16, Command>,
+}
+thread_local!{
+    static COMMANDS: RefCell<CommandTable> = RefCell::new(CommandTable {
+        next: FIRST_ID,
+        commands: HashMap::new(),
+    });
+}
+// Fails once every id is taken, which takes tens of thousands of menu items that are still alive.
+pub(crate) fn allocate(func: Box<dyn FnMut(&Window)>) -> Result<u16, Error> {
+    COMMANDS.with(|table| {
+        let mut table = table.borrow_mut();
+        let count = (LAST_ID - FIRST_ID) as usize + 1;
+        if table.commands.len() >= count {
+            return Err(Error::from_raw(ERROR_NO_MORE_ITEMS));
+        }
+        let mut id = table.next;
+        while table.commands.contains_key(&id) {
+            id = if id == LAST_ID { FIRST_ID } else { id + 1 };
+        }
+        table.next = if id == LAST_ID { FIRST_ID } else { id + 1 };
+        table.commands.insert(id, Rc::new(RefCell::new(func)));
+        Ok(id)
+    })
+}
+// Whether the id belongs to the range handed out automatically.
+pub(crate) fn is_automatic(id: u16) -> bool {
+    (FIRST_ID..=LAST_ID).contains(&id)
+}
+pub(crate) fn free(id: u16) {
+    COMMANDS.with(|table| {
+        table.borrow_mut().commands.remove(&id);
+    });
+}
+// The number of ids in use, for tests checking that menus hand theirs back.
+#[cfg(test)]
+pub(crate) fn count() -> usize {
+    COMMANDS.with(|table| table.borrow().commands.len())
+}
+fn get(id: u16) -> Option<Command> {
+    COMMANDS.with(|table| table.borrow().commands.get(&id).cloned())
+}
+// Returns false if the id wasn't allocated here, in which case it was chosen by hand.
+pub(crate) fn invoke(id: u16, window: &Window) -> bool {
+    // The table must not stay borrowed while the command runs, as it may well build a new menu.
+    match get(id) {
+        Some(command) => {
+            (*command.borrow_mut())(window);
+            true
+        },
+        None => false,
+    }
+}
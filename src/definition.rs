@@ -0,0 +1,446 @@
+#[cfg(windows)]
+use std::cell::RefCell;
+#[cfg(windows)]
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::error::Error as StdError;
+#[cfg(windows)]
+use std::fmt;
+#[cfg(windows)]
+use std::rc::Rc;
+
+#[cfg(windows)]
+use Error;
+#[cfg(windows)]
+use accel::AcceleratorTableBuilder;
+#[cfg(windows)]
+use menu::{Menu, MenuAction, MenuBar, MenuItem, PopupMenu, RadioGroup};
+use menumodel::{MenuCheck, MenuModel, MenuStatus, ModelItem};
+use mnemonic::LabelProblem;
+#[cfg(windows)]
+use shortcut::{ParseShortcutError, Shortcut};
+#[cfg(windows)]
+use window::Window;
+
+// The definitions themselves and their validation build on any platform, loading them into menus
+// needs Windows.
+
+// A menu described as data, so that it can be deserialized from any format serde supports. Items
+// are tagged by a `type` field, for example in TOML:
+//
+//     [[items]]
+//     type = "item"
+//     label = "&Pause"
+//     command = "pause"
+//     shortcut = "Ctrl+P"
+//
+//     [[items]]
+//     type = "separator"
+//
+// Mistakes in the document itself are reported by the deserializer, in its own terms such as a
+// line and column. The `items[i]` paths of `DefinitionError` only cover problems found while
+// loading a well formed definition.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MenuDefinition {
+    pub items: Vec<ItemDefinition>,
+}
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ItemDefinition {
+    Item {
+        label: String,
+        // The name of a command bound with `MenuBindings::command`.
+        #[serde(default)]
+        command: Option<String>,
+        // A fixed command id, reported through `Event::MenuCommand` like any other.
+        #[serde(default)]
+        id: Option<u16>,
+        #[serde(default = "default_status")]
+        status: MenuStatus,
+        #[serde(default = "default_check")]
+        check: MenuCheck,
+        #[serde(default)]
+        shortcut: Option<String>,
+    },
+    Separator,
+    Submenu {
+        label: String,
+        #[serde(default = "default_status")]
+        status: MenuStatus,
+        items: Vec<ItemDefinition>,
+    },
+    Radio {
+        // The name of a group bound with `MenuBindings::radio`.
+        group: String,
+        items: Vec<RadioDefinition>,
+        #[serde(default)]
+        selected: Option<String>,
+    },
+}
+#[derive(Clone, Debug, Deserialize)]
+pub struct RadioDefinition {
+    pub label: String,
+    pub value: String,
+}
+impl MenuDefinition {
+    // Checks the labels of every level, see `MenuModel::validate`. Paths count positions in the
+    // built menu, where each item of a radio group takes up a position of its own.
+    pub fn validate(&self) -> Vec<LabelProblem> {
+        MenuModel { items: to_model(&self.items) }.validate()
+    }
+}
+// Only the labels and the shape of the tree matter for validation, so keys and ids are left
+// empty.
+fn to_model(items: &[ItemDefinition]) -> Vec<ModelItem> {
+    let command = |label: &str, status, check| ModelItem::Command {
+        key: String::new(),
+        label: label.to_owned(),
+        id: 0,
+        status: status,
+        check: check,
+    };
+    let mut model = Vec::new();
+    for item in items {
+        match *item {
+            ItemDefinition::Item { ref label, status, check, .. } => {
+                model.push(command(label, status, check));
+            },
+            ItemDefinition::Separator => model.push(ModelItem::Separator { key: String::new() }),
+            ItemDefinition::Submenu { ref label, status, ref items } => {
+                model.push(ModelItem::Submenu {
+                    key: String::new(),
+                    label: label.clone(),
+                    status: status,
+                    items: to_model(items),
+                });
+            },
+            ItemDefinition::Radio { ref items, .. } => {
+                model.extend(items.iter().map(|item| {
+                    command(&item.label, MenuStatus::Enabled, MenuCheck::RadioUnchecked)
+                }));
+            },
+        }
+    }
+    model
+}
+fn default_status() -> MenuStatus {
+    MenuStatus::Enabled
+}
+fn default_check() -> MenuCheck {
+    MenuCheck::Unchecked
+}
+#[cfg(windows)]
+type CommandHandler = Rc<RefCell<Box<dyn FnMut(&Window)>>>;
+#[cfg(windows)]
+type RadioHandler = Rc<RefCell<Box<dyn FnMut(&str, &Window)>>>;
+// Binds the symbolic command and radio group names used in definitions to closures. A name may
+// be used by several items, which then share the closure.
+#[cfg(windows)]
+pub struct MenuBindings {
+    commands: HashMap<String, CommandHandler>,
+    radios: HashMap<String, RadioHandler>,
+}
+#[cfg(windows)]
+impl Default for MenuBindings {
+    fn default() -> MenuBindings {
+        MenuBindings::new()
+    }
+}
+#[cfg(windows)]
+impl MenuBindings {
+    pub fn new() -> MenuBindings {
+        MenuBindings {
+            commands: HashMap::new(),
+            radios: HashMap::new(),
+        }
+    }
+    pub fn command<T>(mut self, name: &str, func: T) -> MenuBindings
+        where T: FnMut(&Window) + 'static
+    {
+        self.commands.insert(name.to_owned(), Rc::new(RefCell::new(Box::new(func))));
+        self
+    }
+    // Called with the value of the newly selected item in the group.
+    pub fn radio<T>(mut self, name: &str, func: T) -> MenuBindings
+        where T: FnMut(&str, &Window) + 'static
+    {
+        self.radios.insert(name.to_owned(), Rc::new(RefCell::new(Box::new(func))));
+        self
+    }
+}
+// The menu along with the accelerators for the shortcuts declared on its items, which still need
+// to be attached to a window with `Window::set_accelerators`.
+#[cfg(windows)]
+pub struct LoadedMenu<T> {
+    pub menu: T,
+    pub accelerators: AcceleratorTableBuilder,
+}
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum DefinitionErrorKind {
+    UnboundCommand(String),
+    UnboundRadioGroup(String),
+    // An item needs either a command or an id.
+    MissingAction,
+    EmptyRadioGroup,
+    UnknownRadioValue(String),
+    InvalidShortcut(ParseShortcutError),
+    Windows(Error),
+}
+// Identifies the offending item by its path within the definition, such as `items[2].items[0]`.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct DefinitionError {
+    pub path: String,
+    pub kind: DefinitionErrorKind,
+}
+#[cfg(windows)]
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.path)?;
+        match self.kind {
+            DefinitionErrorKind::UnboundCommand(ref name) => {
+                write!(f, "unbound command {:?}", name)
+            },
+            DefinitionErrorKind::UnboundRadioGroup(ref name) => {
+                write!(f, "unbound radio group {:?}", name)
+            },
+            DefinitionErrorKind::MissingAction => {
+                f.write_str("item has neither a command nor an id")
+            },
+            DefinitionErrorKind::EmptyRadioGroup => f.write_str("radio group has no items"),
+            DefinitionErrorKind::UnknownRadioValue(ref value) => {
+                write!(f, "selected value {:?} is not in the group", value)
+            },
+            DefinitionErrorKind::InvalidShortcut(ref err) => write!(f, "{}", err),
+            DefinitionErrorKind::Windows(err) => {
+                write!(f, "failed to build menu: error {}", err.as_raw())
+            },
+        }
+    }
+}
+#[cfg(windows)]
+impl StdError for DefinitionError {}
+#[cfg(windows)]
+impl PopupMenu {
+    pub fn from_definition(
+        def: &MenuDefinition, bindings: &MenuBindings,
+    ) -> Result<LoadedMenu<PopupMenu>, DefinitionError> {
+        let menu = PopupMenu::new().map_err(|e| error("items", DefinitionErrorKind::Windows(e)))?;
+        let accelerators = load(&menu, def, bindings)?;
+        Ok(LoadedMenu { menu: menu, accelerators: accelerators })
+    }
+}
+#[cfg(windows)]
+impl MenuBar {
+    pub fn from_definition(
+        def: &MenuDefinition, bindings: &MenuBindings,
+    ) -> Result<LoadedMenu<MenuBar>, DefinitionError> {
+        let menu = MenuBar::new().map_err(|e| error("items", DefinitionErrorKind::Windows(e)))?;
+        let accelerators = load(&menu, def, bindings)?;
+        Ok(LoadedMenu { menu: menu, accelerators: accelerators })
+    }
+}
+#[cfg(windows)]
+fn load(
+    menu: &Menu, def: &MenuDefinition, bindings: &MenuBindings,
+) -> Result<AcceleratorTableBuilder, DefinitionError> {
+    let mut shortcuts = Vec::new();
+    build(menu, &def.items, bindings, "items", &mut shortcuts)?;
+    Ok(shortcuts.into_iter().fold(AcceleratorTableBuilder::new(), |b, (s, id)| b.add(s, id)))
+}
+#[cfg(windows)]
+fn error(path: &str, kind: DefinitionErrorKind) -> DefinitionError {
+    DefinitionError {
+        path: path.to_owned(),
+        kind: kind,
+    }
+}
+#[cfg(windows)]
+fn build(
+    menu: &Menu, items: &[ItemDefinition], bindings: &MenuBindings, path: &str,
+    shortcuts: &mut Vec<(Shortcut, u16)>,
+) -> Result<(), DefinitionError> {
+    for (i, item) in items.iter().enumerate() {
+        let path = format!("{}[{}]", path, i);
+        let windows = |e| error(&path, DefinitionErrorKind::Windows(e));
+        match *item {
+            ItemDefinition::Item { ref label, ref command, id, status, check, ref shortcut } => {
+                let shortcut = match *shortcut {
+                    Some(ref s) => Some(s.parse::<Shortcut>().map_err(|e| {
+                        error(&path, DefinitionErrorKind::InvalidShortcut(e))
+                    })?),
+                    None => None,
+                };
+                let action = match (command, id) {
+                    (&Some(ref name), _) => {
+                        let handler = bindings.commands.get(name).cloned().ok_or_else(|| {
+                            error(&path, DefinitionErrorKind::UnboundCommand(name.clone()))
+                        })?;
+                        MenuAction::callback(move |w| (*handler.borrow_mut())(w))
+                    },
+                    (&None, Some(id)) => MenuAction::Id(id),
+                    (&None, None) => return Err(error(&path, DefinitionErrorKind::MissingAction)),
+                };
+                let item = menu.append_string(label, action, status, check).map_err(windows)?;
+                // Items with a command or an id are always appended with an id.
+                if let (Some(shortcut), MenuItem::Id(id)) = (shortcut, item) {
+                    shortcuts.push((shortcut, id));
+                }
+            },
+            ItemDefinition::Separator => menu.append_separator().map_err(windows)?,
+            ItemDefinition::Submenu { ref label, status, ref items } => {
+                let child = PopupMenu::new().map_err(windows)?;
+                build(&child, items, bindings, &format!("{}.items", path), shortcuts)?;
+                let action = MenuAction::ChildMenu(child);
+                menu.append_string(label, action, status, MenuCheck::Unchecked).map_err(windows)?;
+            },
+            ItemDefinition::Radio { ref group, ref items, ref selected } => {
+                if items.is_empty() {
+                    return Err(error(&path, DefinitionErrorKind::EmptyRadioGroup));
+                }
+                let handler = bindings.radios.get(group).cloned().ok_or_else(|| {
+                    error(&path, DefinitionErrorKind::UnboundRadioGroup(group.clone()))
+                })?;
+                let mut radio = RadioGroup::new()
+                    .handler(move |value: String, w| (*handler.borrow_mut())(&value, w));
+                for item in items {
+                    radio = radio.item(&item.label, item.value.clone());
+                }
+                if let Some(ref selected) = *selected {
+                    if !items.iter().any(|item| &item.value == selected) {
+                        let kind = DefinitionErrorKind::UnknownRadioValue(selected.clone());
+                        return Err(error(&path, kind));
+                    }
+                    radio = radio.selected(selected.clone());
+                }
+                menu.append_radio_group(radio).map_err(windows)?;
+            },
+        }
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use mnemonic::LabelProblemKind;
+
+    const DEFINITION: &str = r#"{
+        "items": [
+            { "type": "item", "label": "&Pause", "command": "pause", "shortcut": "Ctrl+P" },
+            { "type": "separator" },
+            { "type": "submenu", "label": "&Mode", "items": [
+                { "type": "radio", "group": "mode", "selected": "fast", "items": [
+                    { "label": "&Fast", "value": "fast" },
+                    { "label": "&Quiet", "value": "quiet" }
+                ] },
+                { "type": "item", "label": "&Custom", "id": 7, "status": "grayed" }
+            ] }
+        ]
+    }"#;
+
+    fn parse(text: &str) -> MenuDefinition {
+        serde_json::from_str(text).unwrap()
+    }
+    #[test]
+    fn deserialize() {
+        let def = parse(DEFINITION);
+        assert_eq!(def.items.len(), 3);
+        match def.items[0] {
+            ItemDefinition::Item { ref label, ref command, id, status, check, ref shortcut } => {
+                assert_eq!(label, "&Pause");
+                assert_eq!(command.as_ref().map(|s| &s[..]), Some("pause"));
+                assert_eq!(id, None);
+                assert_eq!((status, check), (MenuStatus::Enabled, MenuCheck::Unchecked));
+                assert_eq!(shortcut.as_ref().map(|s| &s[..]), Some("Ctrl+P"));
+            },
+            ref item => panic!("not an item: {:?}", item),
+        }
+        match def.items[1] {
+            ItemDefinition::Separator => (),
+            ref item => panic!("not a separator: {:?}", item),
+        }
+        let items = match def.items[2] {
+            ItemDefinition::Submenu { ref items, .. } => items,
+            ref item => panic!("not a submenu: {:?}", item),
+        };
+        match items[0] {
+            ItemDefinition::Radio { ref group, ref items, ref selected } => {
+                assert_eq!(group, "mode");
+                let values: Vec<&str> = items.iter().map(|item| &item.value[..]).collect();
+                assert_eq!(values, ["fast", "quiet"]);
+                assert_eq!(selected.as_ref().map(|s| &s[..]), Some("fast"));
+            },
+            ref item => panic!("not a radio group: {:?}", item),
+        }
+        match items[1] {
+            ItemDefinition::Item { id, status, .. } => {
+                assert_eq!((id, status), (Some(7), MenuStatus::Grayed));
+            },
+            ref item => panic!("not an item: {:?}", item),
+        }
+        // Unknown item types are left to the deserializer to report.
+        let text = r#"{ "items": [{ "type": "button", "label": "OK" }] }"#;
+        assert!(serde_json::from_str::<MenuDefinition>(text).is_err());
+    }
+    #[test]
+    fn validate() {
+        assert_eq!(parse(DEFINITION).validate(), []);
+        // Each item of the radio group takes up a position, so the second "&Custom" is at 3.
+        let def = parse(r#"{
+            "items": [
+                { "type": "item", "label": "&Open", "id": 1 },
+                { "type": "submenu", "label": "&Other", "items": [
+                    { "type": "radio", "group": "g", "items": [
+                        { "label": "&Custom", "value": "a" },
+                        { "label": "&Fast", "value": "b" }
+                    ] },
+                    { "type": "separator" },
+                    { "type": "item", "label": "&Cancel", "id": 2 }
+                ] }
+            ]
+        }"#);
+        assert_eq!(def.validate(), [
+            LabelProblem { path: vec![1], kind: LabelProblemKind::DuplicateAccessKey('o', 0) },
+            LabelProblem { path: vec![1, 3], kind: LabelProblemKind::DuplicateAccessKey('c', 0) },
+        ]);
+    }
+    #[cfg(windows)]
+    fn load_error(text: &str, bindings: &MenuBindings) -> String {
+        match PopupMenu::from_definition(&parse(text), bindings) {
+            Ok(_) => panic!("loaded {}", text),
+            Err(err) => err.to_string(),
+        }
+    }
+    #[cfg(windows)]
+    #[test]
+    fn binding_errors() {
+        let bindings = MenuBindings::new().command("pause", |_| ()).radio("mode", |_, _| ());
+        assert!(PopupMenu::from_definition(&parse(DEFINITION), &bindings).is_ok());
+        let nested = r#"{ "items": [
+            { "type": "separator" },
+            { "type": "submenu", "label": "More", "items": [
+                { "type": "item", "label": "Stop", "command": "stop" }
+            ] }
+        ] }"#;
+        assert_eq!(load_error(nested, &bindings), r#"items[1].items[0]: unbound command "stop""#);
+        let text = r#"{ "items": [{ "type": "radio", "group": "size", "items": [] }] }"#;
+        assert_eq!(load_error(text, &bindings), "items[0]: radio group has no items");
+        let text = r#"{ "items": [{ "type": "radio", "group": "size", "items": [
+            { "label": "Big", "value": "big" }
+        ] }] }"#;
+        assert_eq!(load_error(text, &bindings), r#"items[0]: unbound radio group "size""#);
+        let text = r#"{ "items": [{ "type": "radio", "group": "mode", "selected": "slow",
+            "items": [{ "label": "Fast", "value": "fast" }] }] }"#;
+        let message = r#"items[0]: selected value "slow" is not in the group"#;
+        assert_eq!(load_error(text, &bindings), message);
+        let text = r#"{ "items": [{ "type": "item", "label": "Nothing" }] }"#;
+        assert_eq!(load_error(text, &bindings), "items[0]: item has neither a command nor an id");
+        let text = r#"{ "items": [
+            { "type": "item", "label": "Pause", "command": "pause", "shortcut": "Ctrl+" }
+        ] }"#;
+        assert!(load_error(text, &bindings).starts_with("items[0]: "));
+    }
+}
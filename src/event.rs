@@ -1,16 +1,58 @@
 
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use winapi::shared::minwindef::{DWORD, HIWORD, LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::HMENU;
 use winapi::shared::windowsx::{GET_X_LPARAM, GET_Y_LPARAM};
-use winapi::um::winuser::{CREATESTRUCTW, MINMAXINFO, WM_APP};
+use winapi::um::winuser::{
+    CREATESTRUCTW, DRAWITEMSTRUCT, GetAsyncKeyState, GetMessagePos, MEASUREITEMSTRUCT, MINMAXINFO,
+    RegisterWindowMessageW, VK_RETURN, VK_SPACE,
+};
 
-pub(crate) const WM_APP_NOTIFICATION_ICON: u32 = WM_APP + 1;
+use menu::MenuItem;
+use notifyevent::decode_notify_icon;
+pub use notifyevent::{MouseButton, NotifyIconEvent, SelectKey};
+pub(crate) use notifyevent::{WM_APP_NOTIFICATION_ICON, WM_APP_NOTIFICATION_ICON_V3};
+use wide::{FromWide, ToWide};
+
+// Broadcast to top level windows when Explorer starts, with an id that is only known at runtime.
+static TASKBAR_CREATED: AtomicUsize = AtomicUsize::new(0);
+fn taskbar_created() -> UINT {
+    let mut msg = TASKBAR_CREATED.load(Ordering::Relaxed);
+    if msg == 0 {
+        msg = unsafe { RegisterWindowMessageW("TaskbarCreated".to_wide_null().as_ptr()) } as usize;
+        TASKBAR_CREATED.store(msg, Ordering::Relaxed);
+    }
+    msg as UINT
+}
 
 #[derive(Debug)]
 pub enum Event {
     MenuCommand(u16),
     Create(*const CREATESTRUCTW),
     Destroy,
+    // Whether the window became active or inactive.
+    Activate(bool),
+    // The virtual key code of the key pressed.
+    KeyDown(u32),
     GetMinMaxInfo(*mut MINMAXINFO),
+    MeasureItem(*mut MEASUREITEMSTRUCT),
+    DrawItem(*const DRAWITEMSTRUCT),
+    // The boolean is true for popup menus and false for the menu bar.
+    EnterMenuLoop(bool),
+    // Sent right before the submenu opens, after lazy submenus have been filled.
+    InitMenuPopup(HMENU),
+    // The highlighted item changed, or None once the menu closes.
+    MenuSelect(Option<MenuSelection>),
+    UninitMenuPopup(HMENU),
+    // A chosen menu bar item arrives as `MenuCommand` after this, while popup menus return the
+    // choice from `PopupMenu::select`. Anything else means the menu closed without a selection.
+    ExitMenuLoop(bool),
+    SettingChange(Option<OsString>),
+    ThemeChanged,
+    // Explorer was restarted. Notification icons are added back automatically.
+    TaskbarCreated,
+    Timer(usize),
     #[doc(hidden)] Unknown(UINT, WPARAM, LPARAM),
     #[doc(hidden)] NotifyIcon(u16, NotifyIconEvent),
 }
@@ -18,37 +60,80 @@ impl Event {
     pub unsafe fn from_raw(msg: UINT, wparam: WPARAM, lparam: LPARAM) -> Event {
         use winapi::um::winuser as wu;
         match msg {
-            wu::WM_COMMAND if HIWORD(wparam as u32) == 0 => Event::MenuCommand(LOWORD(wparam as u32)),
+            // The high word is 0 for menus and 1 for accelerators, which are treated alike.
+            wu::WM_COMMAND if HIWORD(wparam as u32) <= 1 && lparam == 0 => {
+                Event::MenuCommand(LOWORD(wparam as u32))
+            },
             wu::WM_CREATE => Event::Create(lparam as *const CREATESTRUCTW),
             wu::WM_DESTROY => Event::Destroy,
+            wu::WM_ACTIVATE => Event::Activate(LOWORD(wparam as DWORD) != wu::WA_INACTIVE),
+            wu::WM_KEYDOWN => Event::KeyDown(wparam as u32),
             wu::WM_GETMINMAXINFO => Event::GetMinMaxInfo(lparam as *mut MINMAXINFO),
-            WM_APP_NOTIFICATION_ICON => Event::NotifyIcon(
-                HIWORD(lparam as DWORD),
-                NotifyIconEvent::from_raw(wparam, lparam),
-            ),
+            wu::WM_MEASUREITEM => Event::MeasureItem(lparam as *mut MEASUREITEMSTRUCT),
+            wu::WM_DRAWITEM => Event::DrawItem(lparam as *const DRAWITEMSTRUCT),
+            wu::WM_ENTERMENULOOP => Event::EnterMenuLoop(wparam != 0),
+            wu::WM_INITMENUPOPUP => Event::InitMenuPopup(wparam as HMENU),
+            wu::WM_MENUSELECT => Event::MenuSelect(MenuSelection::from_raw(wparam, lparam)),
+            wu::WM_UNINITMENUPOPUP => Event::UninitMenuPopup(wparam as HMENU),
+            wu::WM_EXITMENULOOP => Event::ExitMenuLoop(wparam != 0),
+            wu::WM_SETTINGCHANGE => Event::SettingChange(if lparam == 0 { None } else {
+                Some(OsString::from_wide_ptr(lparam as *const u16))
+            }),
+            wu::WM_THEMECHANGED => Event::ThemeChanged,
+            wu::WM_TIMER => Event::Timer(wparam),
+            WM_APP_NOTIFICATION_ICON | WM_APP_NOTIFICATION_ICON_V3 => {
+                let cursor = || {
+                    let pos = GetMessagePos() as LPARAM;
+                    (GET_X_LPARAM(pos), GET_Y_LPARAM(pos))
+                };
+                let key = SelectKey::current;
+                let (id, event) = decode_notify_icon(msg, wparam, lparam, cursor, key);
+                Event::NotifyIcon(id, event)
+            },
+            _ if msg != 0 && msg == taskbar_created() => Event::TaskbarCreated,
             _ => Event::Unknown(msg, wparam, lparam),
         }
     }
 }
-#[derive(Debug)]
-pub enum NotifyIconEvent {
-    ContextMenu(i32, i32),
-    MouseMove(i32, i32),
-    Select(i32, i32),
-    Unknown(u32, i32, i32),
+// The item being highlighted, for showing help text about it somewhere.
+#[derive(Clone, Copy, Debug)]
+pub struct MenuSelection {
+    // The menu containing the item.
+    pub menu: HMENU,
+    // Items that open a submenu are referred to by position.
+    pub item: MenuItem,
+    pub submenu: bool,
+    pub disabled: bool,
+    // The item is in the window menu rather than one of ours.
+    pub system: bool,
 }
-impl NotifyIconEvent {
-    unsafe fn from_raw(wparam: WPARAM, lparam: LPARAM) -> NotifyIconEvent {
+impl MenuSelection {
+    fn from_raw(wparam: WPARAM, lparam: LPARAM) -> Option<MenuSelection> {
         use winapi::um::winuser as wu;
-        use winapi::um::shellapi as sa;
-        let x = GET_X_LPARAM(wparam as LPARAM);
-        let y = GET_Y_LPARAM(wparam as LPARAM);
-        let msg = LOWORD(lparam as DWORD) as UINT;
-        match msg {
-            sa::NIN_SELECT => NotifyIconEvent::Select(x, y),
-            wu::WM_CONTEXTMENU => NotifyIconEvent::ContextMenu(x, y),
-            wu::WM_MOUSEMOVE => NotifyIconEvent::MouseMove(x, y),
-            _ => NotifyIconEvent::Unknown(msg, x, y),
+        let flags = HIWORD(wparam as DWORD) as UINT;
+        if flags == 0xFFFF && lparam == 0 {
+            return None;
+        }
+        let item = LOWORD(wparam as DWORD);
+        let submenu = flags & wu::MF_POPUP != 0;
+        Some(MenuSelection {
+            menu: lparam as HMENU,
+            item: if submenu { MenuItem::Position(item as u32) } else { MenuItem::Id(item) },
+            submenu: submenu,
+            disabled: flags & (wu::MF_DISABLED | wu::MF_GRAYED) != 0,
+            system: flags & wu::MF_SYSMENU != 0,
+        })
+    }
+}
+impl SelectKey {
+    fn current() -> SelectKey {
+        let down = |key| unsafe { GetAsyncKeyState(key) } as u16 & 0x8000 != 0;
+        if down(VK_RETURN) {
+            SelectKey::Enter
+        } else if down(VK_SPACE) {
+            SelectKey::Space
+        } else {
+            SelectKey::Unknown
         }
     }
 }
@@ -61,3 +146,4 @@ impl EventResponse {
         self.0
     }
 }
+
@@ -0,0 +1,149 @@
+use std::mem::{size_of, zeroed};
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+use winapi::shared::windef::{HMONITOR, POINT, RECT};
+use winapi::um::shellapi::{
+    ABE_BOTTOM, ABE_LEFT, ABE_RIGHT, ABE_TOP, ABM_GETTASKBARPOS, APPBARDATA, SHAppBarMessage,
+};
+use winapi::um::winuser::{
+    GetCursorPos, GetMonitorInfoW, MONITORINFO, MONITOR_DEFAULTTONEAREST,
+    MONITOR_DEFAULTTOPRIMARY, MonitorFromPoint, MonitorFromRect, SW_SHOW, SetForegroundWindow,
+    ShowWindow, VK_ESCAPE,
+};
+
+use Error;
+use class::Class;
+use event::{Event, EventResponse};
+use geometry::Rect;
+use notifyicon::NotifyIcon;
+use placement::{Edge, place_flyout, taskbar_edge, taskbar_from_work_area};
+use window::{Window, WindowBuilder, WindowRef};
+
+pub struct Taskbar {
+    pub edge: Edge,
+    pub rect: Rect,
+}
+impl Taskbar {
+    // The primary taskbar, which is the one holding the notification area. When the shell can't
+    // say, such as while Explorer isn't running, it is guessed from the primary monitor.
+    pub fn query() -> Result<Taskbar, Error> {
+        let mut data: APPBARDATA = unsafe { zeroed() };
+        data.cbSize = size_of::<APPBARDATA>() as u32;
+        // It doesn't set the last error when it fails, so there would be nothing to report.
+        if unsafe { SHAppBarMessage(ABM_GETTASKBARPOS, &mut data) } == 0 {
+            let origin = POINT { x: 0, y: 0 };
+            let primary = unsafe { MonitorFromPoint(origin, MONITOR_DEFAULTTOPRIMARY) };
+            let (monitor, work) = monitor_info(primary)?;
+            let (edge, rect) = taskbar_from_work_area(monitor, work);
+            return Ok(Taskbar { edge: edge, rect: rect });
+        }
+        let r = data.rc;
+        let rect = Rect::new(r.left, r.top, r.right, r.bottom);
+        let edge = match data.uEdge {
+            ABE_LEFT => Edge::Left,
+            ABE_TOP => Edge::Top,
+            ABE_RIGHT => Edge::Right,
+            ABE_BOTTOM => Edge::Bottom,
+            _ => taskbar_edge(rect, monitor_areas(rect)?.0),
+        };
+        Ok(Taskbar { edge: edge, rect: rect })
+    }
+}
+// The work area of the monitor nearest to the rectangle, which leaves out the taskbar and other
+// docked bars.
+pub fn work_area(near: Rect) -> Result<Rect, Error> {
+    monitor_areas(near).map(|(_, work)| work)
+}
+// The whole area and the work area of the monitor nearest to the rectangle.
+fn monitor_areas(near: Rect) -> Result<(Rect, Rect), Error> {
+    let near = RECT { left: near.left, top: near.top, right: near.right, bottom: near.bottom };
+    monitor_info(unsafe { MonitorFromRect(&near, MONITOR_DEFAULTTONEAREST) })
+}
+fn monitor_info(monitor: HMONITOR) -> Result<(Rect, Rect), Error> {
+    let mut info: MONITORINFO = unsafe { zeroed() };
+    info.cbSize = size_of::<MONITORINFO>() as u32;
+    if unsafe { GetMonitorInfoW(monitor, &mut info) } == 0 {
+        return Err(Error::get_last_error());
+    }
+    let (m, w) = (info.rcMonitor, info.rcWork);
+    let monitor = Rect::new(m.left, m.top, m.right, m.bottom);
+    Ok((monitor, Rect::new(w.left, w.top, w.right, w.bottom)))
+}
+type FlyoutHandler = Box<dyn Fn(Event, &Window) -> Option<EventResponse> + Send>;
+// A small panel shown beside a notification icon, which closes as soon as it loses focus or
+// Escape is pressed, like the volume and network flyouts.
+pub struct FlyoutBuilder {
+    width: i32,
+    height: i32,
+    gap: i32,
+    class: Option<Class>,
+    handler: Option<FlyoutHandler>,
+}
+impl FlyoutBuilder {
+    pub fn new(width: i32, height: i32) -> FlyoutBuilder {
+        FlyoutBuilder {
+            width: width,
+            height: height,
+            gap: 12,
+            class: None,
+            handler: None,
+        }
+    }
+    // The space left between the flyout and the taskbar or the edges of the screen.
+    pub fn gap(mut self, gap: i32) -> FlyoutBuilder {
+        self.gap = gap;
+        self
+    }
+    pub fn class(mut self, class: Class) -> FlyoutBuilder {
+        self.class = Some(class);
+        self
+    }
+    // Sees every event of the flyout, including the ones that close it.
+    pub fn handler<T>(
+        mut self, handler: T
+    ) -> FlyoutBuilder where T: Fn(Event, &Window) -> Option<EventResponse> + Send + 'static {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+    // Shows the flyout next to the icon, or next to the pointer when the icon can't be found,
+    // such as when it is in the overflow area. Must be called from the thread of the icon. Fails
+    // with ERROR_INVALID_PARAMETER when no class was given.
+    pub fn show(self, icon: &NotifyIcon) -> Result<WindowRef, Error> {
+        let class = match self.class {
+            Some(class) => class,
+            None => return Err(Error::from_raw(ERROR_INVALID_PARAMETER)),
+        };
+        let anchor = match icon.rect() {
+            Ok(rect) => rect,
+            Err(_) => {
+                let mut p = POINT { x: 0, y: 0 };
+                if unsafe { GetCursorPos(&mut p) } == 0 {
+                    return Err(Error::get_last_error());
+                }
+                Rect::new(p.x, p.y, p.x + 1, p.y + 1)
+            },
+        };
+        let edge = Taskbar::query()?.edge;
+        let area = work_area(anchor)?;
+        let rect = place_flyout(anchor, self.width, self.height, edge, area, self.gap);
+        let handler = self.handler.unwrap_or_else(|| Box::new(|_, _| None));
+        let flyout = WindowBuilder::new().class(class).handler(move |event, window| {
+            let dismiss = match event {
+                Event::Activate(active) => !active,
+                Event::KeyDown(key) => key == VK_ESCAPE as u32,
+                _ => false,
+            };
+            if dismiss {
+                // Closing is only posted, so the handler still sees the event first.
+                let _ = window.as_ref().close();
+            }
+            handler(event, window)
+        }).create_popup(rect)?;
+        let hwnd = flyout.as_raw()?;
+        unsafe {
+            ShowWindow(hwnd, SW_SHOW);
+            // Without focus it would never be deactivated, and so never close on its own.
+            SetForegroundWindow(hwnd);
+        }
+        Ok(flyout)
+    }
+}
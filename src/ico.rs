@@ -0,0 +1,218 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+// Reads the directory of .ico files, whose images are either PNG files or DIBs without the file
+// header, and picks the one to use for a size.
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const DIR_SIZE: usize = 6;
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IcoError {
+    // Shorter than the directory it claims to have.
+    Truncated,
+    // Not an icon file, which includes cursor files.
+    NotIcon,
+    Empty,
+    // The image at this index of the directory lies outside the file or can't be read.
+    BadImage(usize),
+}
+impl fmt::Display for IcoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IcoError::Truncated => f.write_str("icon file is truncated"),
+            IcoError::NotIcon => f.write_str("not an icon file"),
+            IcoError::Empty => f.write_str("icon file has no images"),
+            IcoError::BadImage(index) => write!(f, "image {} of the icon file is invalid", index),
+        }
+    }
+}
+impl StdError for IcoError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+}
+// One image of an icon file. The size and depth come from the image itself rather than from the
+// directory, which can't say 256 or more and is sometimes plain wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IcoImage<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub bit_count: u16,
+    pub format: ImageFormat,
+    // The image as stored in the file, which is what CreateIconFromResourceEx takes.
+    pub data: &'a [u8],
+}
+pub fn parse<'a>(bytes: &'a [u8]) -> Result<Vec<IcoImage<'a>>, IcoError> {
+    if bytes.len() < DIR_SIZE {
+        return Err(IcoError::Truncated);
+    }
+    if u16_at(bytes, 0) != 0 || u16_at(bytes, 2) != 1 {
+        return Err(IcoError::NotIcon);
+    }
+    let count = u16_at(bytes, 4) as usize;
+    if count == 0 {
+        return Err(IcoError::Empty);
+    }
+    if bytes.len() < DIR_SIZE + count * ENTRY_SIZE {
+        return Err(IcoError::Truncated);
+    }
+    (0..count).map(|index| {
+        let entry = DIR_SIZE + index * ENTRY_SIZE;
+        let size = u32_at(bytes, entry + 8) as usize;
+        let offset = u32_at(bytes, entry + 12) as usize;
+        let data = offset.checked_add(size)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or(IcoError::BadImage(index))?;
+        read_image(data).ok_or(IcoError::BadImage(index))
+    }).collect()
+}
+fn read_image<'a>(data: &'a [u8]) -> Option<IcoImage<'a>> {
+    if data.starts_with(PNG_SIGNATURE) {
+        // The IHDR chunk always comes first.
+        if data.len() < 33 || &data[12..16] != b"IHDR" {
+            return None;
+        }
+        let bits_per_channel = data[24] as u16;
+        let channels = match data[25] {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => return None,
+        };
+        return Some(IcoImage {
+            width: u32_be_at(data, 16),
+            height: u32_be_at(data, 20),
+            bit_count: bits_per_channel * channels,
+            format: ImageFormat::Png,
+            data: data,
+        });
+    }
+    if data.len() < 40 || u32_at(data, 0) < 40 {
+        return None;
+    }
+    // The height covers both the color bitmap and the mask below it.
+    let width = u32_at(data, 4) as i32;
+    let height = u32_at(data, 8) as i32 / 2;
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    Some(IcoImage {
+        width: width as u32,
+        height: height as u32,
+        bit_count: u16_at(data, 14),
+        format: ImageFormat::Bmp,
+        data: data,
+    })
+}
+// The size in pixels that a size meant for 96 DPI has at the given DPI, rounded to the nearest
+// pixel and capped at what fits.
+pub fn scale_for_dpi(size: u32, dpi: u32) -> u32 {
+    ((size as u64 * dpi as u64 + 48) / 96).min(u32::MAX as u64) as u32
+}
+// Picks the image that looks best at the size: one of that size, else the smallest larger one as
+// shrinking loses less than growing, else the largest one. Among images of the same size the one
+// with the most colors wins. Non-square images are judged by their larger side.
+pub fn select<'a, 'b>(images: &'b [IcoImage<'a>], size: u32) -> Option<&'b IcoImage<'a>> {
+    images.iter().min_by_key(|image| {
+        let side = image.width.max(image.height);
+        let distance = if side >= size { (0, side - size) } else { (1, size - side) };
+        (distance, !image.bit_count)
+    })
+}
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    bytes[at] as u16 | (bytes[at + 1] as u16) << 8
+}
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u16_at(bytes, at) as u32 | (u16_at(bytes, at + 2) as u32) << 16
+}
+fn u32_be_at(bytes: &[u8], at: usize) -> u32 {
+    (bytes[at] as u32) << 24 | (bytes[at + 1] as u32) << 16 | (bytes[at + 2] as u32) << 8
+        | bytes[at + 3] as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made by tests/fixtures/generate.py.
+    const BMP: &[u8] = include_bytes!("../tests/fixtures/bmp.ico");
+    const PNG: &[u8] = include_bytes!("../tests/fixtures/png.ico");
+    const TRUNCATED: &[u8] = include_bytes!("../tests/fixtures/truncated.ico");
+    const BAD_OFFSET: &[u8] = include_bytes!("../tests/fixtures/bad_offset.ico");
+    const CURSOR: &[u8] = include_bytes!("../tests/fixtures/cursor.cur");
+
+    fn sizes(images: &[IcoImage]) -> Vec<(u32, u32, u16, ImageFormat)> {
+        images.iter().map(|i| (i.width, i.height, i.bit_count, i.format)).collect()
+    }
+
+    #[test]
+    fn parse_bmp() {
+        let images = parse(BMP).unwrap();
+        assert_eq!(sizes(&images), vec![
+            (16, 16, 32, ImageFormat::Bmp),
+            (32, 32, 8, ImageFormat::Bmp),
+            (32, 32, 32, ImageFormat::Bmp),
+            (48, 48, 4, ImageFormat::Bmp),
+        ]);
+        assert_eq!(u32_at(images[0].data, 0), 40);
+    }
+    #[test]
+    fn parse_png() {
+        // The directory says 0 for 256, the size is read from the PNG header instead.
+        let images = parse(PNG).unwrap();
+        assert_eq!(sizes(&images), vec![
+            (16, 16, 32, ImageFormat::Bmp),
+            (256, 256, 32, ImageFormat::Png),
+        ]);
+        assert!(images[1].data.starts_with(PNG_SIGNATURE));
+    }
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse(TRUNCATED), Err(IcoError::Truncated));
+        assert_eq!(parse(&BMP[..4]), Err(IcoError::Truncated));
+        assert_eq!(parse(BAD_OFFSET), Err(IcoError::BadImage(1)));
+        assert_eq!(parse(CURSOR), Err(IcoError::NotIcon));
+        assert_eq!(parse(&[0, 0, 1, 0, 0, 0]), Err(IcoError::Empty));
+        // A size that reaches past the end of the file.
+        let mut bytes = BMP.to_vec();
+        bytes[6 + 8] = 0xFF;
+        bytes[6 + 11] = 0xFF;
+        assert_eq!(parse(&bytes), Err(IcoError::BadImage(0)));
+    }
+    #[test]
+    fn select_size() {
+        let images = parse(BMP).unwrap();
+        let selected = |size| select(&images, size).map(|i| (i.width, i.bit_count));
+        // The exact size, preferring more colors.
+        assert_eq!(selected(16), Some((16, 32)));
+        assert_eq!(selected(32), Some((32, 32)));
+        // The next larger size.
+        assert_eq!(selected(20), Some((32, 32)));
+        assert_eq!(selected(40), Some((48, 4)));
+        // The largest when all are smaller.
+        assert_eq!(selected(64), Some((48, 4)));
+        assert_eq!(select(&[], 16), None);
+        let images = parse(PNG).unwrap();
+        let selected = |size| select(&images, size).map(|i| i.format);
+        assert_eq!(selected(16), Some(ImageFormat::Bmp));
+        assert_eq!(selected(24), Some(ImageFormat::Png));
+        assert_eq!(selected(512), Some(ImageFormat::Png));
+    }
+    #[test]
+    fn scale() {
+        assert_eq!(scale_for_dpi(16, 96), 16);
+        assert_eq!(scale_for_dpi(16, 120), 20);
+        assert_eq!(scale_for_dpi(16, 144), 24);
+        assert_eq!(scale_for_dpi(32, 168), 56);
+        assert_eq!(scale_for_dpi(15, 120), 19);
+        assert_eq!(scale_for_dpi(0, 192), 0);
+        assert_eq!(scale_for_dpi(u32::MAX, 96), u32::MAX);
+        assert_eq!(scale_for_dpi(u32::MAX, 192), u32::MAX);
+        assert_eq!(scale_for_dpi(1 << 24, 480), 5 << 24);
+    }
+}
@@ -1,25 +1,273 @@
 
-use std::mem::forget;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem::{forget, size_of, zeroed};
+use std::path::Path;
 use std::ptr::null_mut;
+use winapi::shared::minwindef::TRUE;
 use winapi::um::libloaderapi::GetModuleHandleW;
-use winapi::um::winuser::{LoadIconW, MAKEINTRESOURCEW};
+use winapi::um::shellapi::{
+    SHGSI_ICON, SHGSI_LARGEICON, SHGSI_SMALLICON, SHGetStockIconInfo, SHSTOCKICONID,
+    SHSTOCKICONINFO,
+};
+use winapi::um::winnt::LPCWSTR;
+use winapi::um::winuser::{
+    CopyIcon, CreateIconFromResourceEx, CreateIconIndirect, DestroyIcon, ICONINFO, IDI_APPLICATION,
+    IDI_ERROR, IDI_INFORMATION, IDI_QUESTION, IDI_SHIELD, IDI_WARNING, IDI_WINLOGO, IMAGE_ICON,
+    LR_DEFAULTCOLOR, LR_DEFAULTSIZE, LoadIconW, LoadImageW, MAKEINTRESOURCEW,
+};
 use winapi::shared::windef::HICON;
 
 use Error;
+use bitmap::{Bitmap, check_pixels, icon_pixels, icon_size};
+use ico::{self, IcoError};
+use pixel;
 
+#[derive(Debug)]
+pub enum IconError {
+    Io(io::Error),
+    Ico(IcoError),
+    Windows(Error),
+}
+impl From<io::Error> for IconError {
+    fn from(err: io::Error) -> IconError {
+        IconError::Io(err)
+    }
+}
+impl From<IcoError> for IconError {
+    fn from(err: IcoError) -> IconError {
+        IconError::Ico(err)
+    }
+}
+impl From<Error> for IconError {
+    fn from(err: Error) -> IconError {
+        IconError::Windows(err)
+    }
+}
+impl fmt::Display for IconError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IconError::Io(ref err) => write!(f, "failed to read icon: {}", err),
+            IconError::Ico(ref err) => write!(f, "{}", err),
+            IconError::Windows(err) => write!(f, "failed to create icon: error {}", err.as_raw()),
+        }
+    }
+}
+impl StdError for IconError {}
+
+// An icon of our own, destroyed when dropped. Icons owned by the system are `SharedIcon`s.
 pub struct Icon(HICON);
 
 impl Icon {
     pub unsafe fn from_resource(id: u16) -> Result<Icon, Error> {
-        let icon = LoadIconW(GetModuleHandleW(null_mut()), MAKEINTRESOURCEW(id));
+        // Unlike LoadIconW, this makes a copy that is ours to destroy.
+        let icon = LoadImageW(
+            GetModuleHandleW(null_mut()), MAKEINTRESOURCEW(id), IMAGE_ICON, 0, 0, LR_DEFAULTSIZE,
+        ) as HICON;
         if icon.is_null() {
             return Err(Error::get_last_error());
         }
         Ok(Icon(icon))
     }
+    /// Takes over an icon that is ours to destroy, such as one from `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// The icon must be valid and owned by nothing else, as it is destroyed on drop. Icons owned
+    /// by the system, such as the ones LoadIconW returns, go to `SharedIcon::from_raw` instead.
+    pub unsafe fn from_raw(icon: HICON) -> Icon {
+        Icon(icon)
+    }
+    // One of the icons the shell uses, such as `SIID_SHIELD` or `SIID_FOLDER`.
+    pub fn stock(id: SHSTOCKICONID, size: IconSize) -> Result<Icon, Error> {
+        let mut info: SHSTOCKICONINFO = unsafe { zeroed() };
+        info.cbSize = size_of::<SHSTOCKICONINFO>() as u32;
+        let flags = SHGSI_ICON | match size {
+            IconSize::Small => SHGSI_SMALLICON,
+            IconSize::Large => SHGSI_LARGEICON,
+        };
+        let hr = unsafe { SHGetStockIconInfo(id, flags, &mut info) };
+        if hr < 0 {
+            return Err(Error::from_raw(hr as u32));
+        }
+        Ok(Icon(info.hIcon))
+    }
+    // Loads the image of an .ico file that suits `size` at `dpi` best, where the size is in pixels
+    // at 96 DPI, for example 16 for a notification icon. It is scaled to the exact size if the file
+    // doesn't have one.
+    pub fn from_file<P: AsRef<Path>>(path: P, size: u32, dpi: u32) -> Result<Icon, IconError> {
+        Icon::from_bytes(&fs::read(path)?, size, dpi)
+    }
+    // Like `from_file`, for the contents of an .ico file, such as one from `include_bytes!`.
+    pub fn from_bytes(bytes: &[u8], size: u32, dpi: u32) -> Result<Icon, IconError> {
+        let images = ico::parse(bytes)?;
+        let size = ico::scale_for_dpi(size, dpi);
+        let image = ico::select(&images, size).ok_or(IcoError::Empty)?;
+        // The data is only read, despite the pointer not being const.
+        let icon = unsafe { CreateIconFromResourceEx(
+            image.data.as_ptr() as *mut u8, image.data.len() as u32, TRUE, 0x0003_0000,
+            size as i32, size as i32, LR_DEFAULTCOLOR,
+        )};
+        if icon.is_null() {
+            return Err(Error::get_last_error().into());
+        }
+        Ok(Icon(icon))
+    }
+    // Pixels are top-down rows of RGBA, with the color not multiplied by alpha. Fully transparent
+    // pixels are also left out of the mask, for where alpha isn't supported. An empty size or the
+    // wrong number of pixels is an invalid parameter.
+    pub fn from_rgba(width: u32, height: u32, pixels: &[u8]) -> Result<Icon, Error> {
+        check_pixels(width, height, pixels)?;
+        let mut bgra = pixels.to_vec();
+        pixel::swap_red_blue(&mut bgra);
+        let color = Bitmap::from_bgra(width, height, &bgra)?;
+        let mask = Bitmap::monochrome(width, height, &pixel::and_mask(width, height, pixels))?;
+        let mut info = ICONINFO {
+            fIcon: TRUE,
+            xHotspot: 0,
+            yHotspot: 0,
+            hbmMask: mask.as_raw(),
+            hbmColor: color.as_raw(),
+        };
+        // The bitmaps are copied, so they can go right after.
+        let icon = unsafe { CreateIconIndirect(&mut info) };
+        if icon.is_null() {
+            return Err(Error::get_last_error());
+        }
+        Ok(Icon(icon))
+    }
+    // The width and height of the icon in pixels.
+    pub fn size(&self) -> Result<(u32, u32), Error> {
+        unsafe { icon_size(self.0) }
+    }
+    // The pixels at the size of the icon, in the layout `from_rgba` takes. Icons without an alpha
+    // channel get one from their mask.
+    pub fn to_rgba(&self) -> Result<Vec<u8>, Error> {
+        let (width, height) = self.size()?;
+        let mut pixels = unsafe { icon_pixels(self.0, width, height)? };
+        pixel::swap_red_blue(&mut pixels);
+        Ok(pixels)
+    }
+    // A copy to own separately, as the same icon can't be destroyed twice.
+    pub fn try_clone(&self) -> Result<Icon, Error> {
+        copy_icon(self.0)
+    }
+    pub fn as_raw(&self) -> HICON {
+        self.0
+    }
+    // Hands the icon over to the caller, who has to destroy it.
     pub fn into_raw(self) -> HICON {
         let icon = self.0;
         forget(self);
         icon
     }
 }
+impl Drop for Icon {
+    fn drop(&mut self) {
+        // Failing means the handle was destroyed elsewhere, which is a bug but no reason to end
+        // the process from inside a drop.
+        let destroyed = unsafe { DestroyIcon(self.0) } != 0;
+        debug_assert!(destroyed, "Failed to destroy icon: {:?}", Error::get_last_error());
+    }
+}
+fn copy_icon(icon: HICON) -> Result<Icon, Error> {
+    let copy = unsafe { CopyIcon(icon) };
+    if copy.is_null() {
+        return Err(Error::get_last_error());
+    }
+    Ok(Icon(copy))
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemIcon {
+    Application,
+    Error,
+    Information,
+    Question,
+    Warning,
+    WinLogo,
+    // The UAC shield.
+    Shield,
+}
+impl SystemIcon {
+    fn as_raw(self) -> LPCWSTR {
+        match self {
+            SystemIcon::Application => IDI_APPLICATION,
+            SystemIcon::Error => IDI_ERROR,
+            SystemIcon::Information => IDI_INFORMATION,
+            SystemIcon::Question => IDI_QUESTION,
+            SystemIcon::Warning => IDI_WARNING,
+            SystemIcon::WinLogo => IDI_WINLOGO,
+            SystemIcon::Shield => IDI_SHIELD,
+        }
+    }
+}
+// An icon owned by the system, which lives as long as the process and must not be destroyed.
+// Anything that keeps icons takes an `Icon`, which `copy` makes.
+#[derive(Clone, Copy)]
+pub struct SharedIcon(HICON);
+impl SharedIcon {
+    // The icon in the large size, as LoadIconW can't load any other.
+    pub fn system(icon: SystemIcon) -> Result<SharedIcon, Error> {
+        let icon = unsafe { LoadIconW(null_mut(), icon.as_raw()) };
+        if icon.is_null() {
+            return Err(Error::get_last_error());
+        }
+        Ok(SharedIcon(icon))
+    }
+    /// # Safety
+    ///
+    /// The icon must stay valid for as long as it is used, which holds for icons owned by the
+    /// system. It is never destroyed from here.
+    pub unsafe fn from_raw(icon: HICON) -> SharedIcon {
+        SharedIcon(icon)
+    }
+    pub fn copy(&self) -> Result<Icon, Error> {
+        copy_icon(self.0)
+    }
+    pub fn as_raw(&self) -> HICON {
+        self.0
+    }
+}
+// The two sizes the system shows windows and files in: the small one in title bars and the
+// taskbar, the large one in Alt+Tab.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IconSize {
+    Small,
+    Large,
+}
+impl IconSize {
+    // In pixels at 96 DPI.
+    pub fn pixels(self) -> u32 {
+        match self {
+            IconSize::Small => 16,
+            IconSize::Large => 32,
+        }
+    }
+}
+pub struct IconPair {
+    pub small: Icon,
+    pub large: Icon,
+}
+impl IconPair {
+    pub fn new(small: Icon, large: Icon) -> IconPair {
+        IconPair { small: small, large: large }
+    }
+    // Loads both sizes from one .ico file, each from the image that suits it best.
+    pub fn from_file<P: AsRef<Path>>(path: P, dpi: u32) -> Result<IconPair, IconError> {
+        IconPair::from_bytes(&fs::read(path)?, dpi)
+    }
+    pub fn from_bytes(bytes: &[u8], dpi: u32) -> Result<IconPair, IconError> {
+        Ok(IconPair {
+            small: Icon::from_bytes(bytes, IconSize::Small.pixels(), dpi)?,
+            large: Icon::from_bytes(bytes, IconSize::Large.pixels(), dpi)?,
+        })
+    }
+    pub fn stock(id: SHSTOCKICONID) -> Result<IconPair, Error> {
+        Ok(IconPair {
+            small: Icon::stock(id, IconSize::Small)?,
+            large: Icon::stock(id, IconSize::Large)?,
+        })
+    }
+}
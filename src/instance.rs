@@ -0,0 +1,22 @@
+// Naming of the lock that keeps a second instance of an app from running, kept apart from
+// `trayapp` so that it can be tested anywhere.
+
+// The name of the mutex held by a running instance of the app. It is local to the session, so
+// that every user logged in can run their own instance. Backslashes separate namespaces in object
+// names, so any in the app name are replaced to keep it within the session namespace.
+pub fn mutex_name(app: &str) -> String {
+    format!("Local\\{}", app.replace('\\', "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(mutex_name("Tray Clock"), "Local\\Tray Clock");
+        assert_eq!(mutex_name("Vendor\\App"), "Local\\Vendor_App");
+        assert_eq!(mutex_name("Global\\App"), "Local\\Global_App");
+        assert_eq!(mutex_name("Ünïcode"), "Local\\Ünïcode");
+    }
+}
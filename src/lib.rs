@@ -1,25 +1,80 @@
 //#![windows_subsystem = "windows"]
 #![deny(unreachable_patterns)]
+// Written for the 2015 edition, before field init shorthand and default binding modes.
+#![allow(clippy::redundant_field_names, clippy::needless_borrowed_reference)]
 extern crate winapi;
+#[cfg(all(windows, feature = "trace"))]
+#[macro_use] extern crate log;
+#[cfg(feature = "definitions")]
+extern crate serde;
+#[cfg(feature = "definitions")]
+#[macro_use] extern crate serde_derive;
+// These only compute things and build on any platform, so that their tests run without Windows.
+#[cfg(feature = "definitions")]
+pub mod definition;
+pub mod geometry;
+pub mod ico;
+pub mod instance;
+pub mod menumodel;
+pub mod mnemonic;
+pub mod msgname;
+pub mod notifyevent;
+pub mod pixel;
+pub mod placement;
+pub mod shortcut;
+#[cfg(windows)]
+pub mod accel;
+#[cfg(windows)]
+pub mod bitmap;
+#[cfg(windows)]
 pub mod brush;
+#[cfg(windows)]
 pub mod class;
+#[cfg(windows)]
+mod command;
+#[cfg(windows)]
 pub mod event;
+#[cfg(windows)]
+pub mod flyout;
+#[cfg(windows)]
 pub mod icon;
+#[cfg(windows)]
 mod wndproc;
+#[cfg(windows)]
 pub mod menu;
+#[cfg(windows)]
 pub mod notifyicon;
+#[cfg(windows)]
+pub mod ownerdraw;
+#[cfg(windows)]
+pub mod retained;
+#[cfg(windows)]
+pub mod theme;
+#[cfg(all(windows, feature = "trace"))]
+mod trace;
+#[cfg(windows)]
+pub mod trayapp;
+#[cfg(windows)]
 mod wide;
+#[cfg(windows)]
 pub mod window;
 
+#[cfg(windows)]
 use std::ptr::null_mut;
+#[cfg(windows)]
 use winapi::shared::winerror::{FACILITY_WIN32};
+#[cfg(windows)]
 use winapi::um::errhandlingapi::{FatalAppExitW, GetLastError, SetLastError};
+#[cfg(windows)]
 use winapi::um::winuser::{MessageBoxW};
 
+#[cfg(windows)]
 use wide::ToWide;
 
+#[cfg(windows)]
 #[derive(Clone, Copy, Debug)]
 pub struct Error(u32);
+#[cfg(windows)]
 impl Error {
     fn from_raw(code: u32) -> Error {
         Error(code)
@@ -45,6 +100,7 @@ impl Error {
     }
 }
 
+#[cfg(windows)]
 pub fn message_box(text: &str, caption: &str, flags: u32) -> Result<i32, Error> {
     let ret = unsafe {
         MessageBoxW(
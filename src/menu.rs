@@ -1,66 +1,504 @@
 
-use std::mem::forget;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::{forget, size_of, take, zeroed};
 use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
 use std::ptr::{null, null_mut};
-use winapi::shared::windef::{HMENU};
+use winapi::shared::minwindef::{BOOL, FALSE, TRUE};
+use winapi::shared::windef::{HBITMAP, HMENU, RECT};
+use winapi::shared::winerror::{ERROR_INVALID_PARAMETER, ERROR_MENU_ITEM_NOT_FOUND};
 use winapi::um::winuser::*;
 
 use Error;
+use accel::AcceleratorTable;
+use bitmap::Bitmap;
+use brush::Brush;
+use command;
+use geometry::Rect;
+use icon::Icon;
+use ownerdraw::{self, OwnerDrawn};
 use wide::ToWide;
 use window::Window;
 
-pub enum MenuStatus {
-    Enabled,
-    Disabled,
-    Grayed,
+pub use menumodel::{MenuCheck, MenuStatus};
+
+impl MenuStatus {
+    fn as_flags(&self) -> u32 {
+        match *self {
+            MenuStatus::Enabled => MF_ENABLED,
+            MenuStatus::Disabled => MF_DISABLED,
+            MenuStatus::Grayed => MF_GRAYED,
+        }
+    }
+    fn from_flags(flags: u32) -> MenuStatus {
+        if flags & MF_GRAYED != 0 { MenuStatus::Grayed }
+        else if flags & MF_DISABLED != 0 { MenuStatus::Disabled }
+        else { MenuStatus::Enabled }
+    }
+}
+impl MenuCheck {
+    fn as_flags(&self) -> u32 {
+        match *self {
+            MenuCheck::Checked | MenuCheck::Radio => MF_CHECKED,
+            MenuCheck::Unchecked | MenuCheck::RadioUnchecked => MF_UNCHECKED,
+        }
+    }
+    fn as_type(&self) -> u32 {
+        match *self {
+            MenuCheck::Checked | MenuCheck::Unchecked => 0,
+            MenuCheck::Radio | MenuCheck::RadioUnchecked => MFT_RADIOCHECK,
+        }
+    }
+    fn from_raw(ty: u32, state: u32) -> MenuCheck {
+        match (ty & MFT_RADIOCHECK != 0, state & MF_CHECKED != 0) {
+            (false, true) => MenuCheck::Checked,
+            (false, false) => MenuCheck::Unchecked,
+            (true, true) => MenuCheck::Radio,
+            (true, false) => MenuCheck::RadioUnchecked,
+        }
+    }
+}
+// Refers to an item either by its command id or by its zero based position within the menu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuItem {
+    Id(u16),
+    Position(u32),
+}
+impl MenuItem {
+    fn as_raw(&self) -> u32 {
+        match *self {
+            MenuItem::Id(id) => id as u32,
+            MenuItem::Position(n) => n,
+        }
+    }
+    fn as_flags(&self) -> u32 {
+        match *self {
+            MenuItem::Id(_) => MF_BYCOMMAND,
+            MenuItem::Position(_) => MF_BYPOSITION,
+        }
+    }
+    fn by_position(&self) -> BOOL {
+        match *self {
+            MenuItem::Id(_) => FALSE,
+            MenuItem::Position(_) => TRUE,
+        }
+    }
 }
-pub enum MenuCheck {
-    Checked,
-    Unchecked,
+#[derive(Clone, Debug)]
+pub struct MenuItemInfo {
+    // Items opening a submenu have one too when they were added here, but not when they come from
+    // a menu built elsewhere.
+    pub id: Option<u16>,
+    pub text: Option<String>,
+    pub status: MenuStatus,
+    pub check: MenuCheck,
+    pub separator: bool,
+    // Still owned by the menu the item belongs to.
+    pub submenu: Option<HMENU>,
 }
+type LazyHandler = Box<dyn FnMut(&Menu, &Window)>;
 pub enum MenuAction {
+    // Sent to the window as `Event::MenuCommand`. Ids from 0x8000 up to 0xFFFE are handed out to
+    // callbacks, so menus reject them with ERROR_INVALID_PARAMETER.
     Id(u16),
     ChildMenu(PopupMenu),
+    // Runs the closure when the item is chosen, using a command id allocated automatically.
+    Callback(Box<dyn FnMut(&Window)>),
+    // Opens an empty submenu which the closure fills every time right before it is shown.
+    LazyMenu(LazyHandler),
+}
+impl MenuAction {
+    pub fn callback<T>(func: T) -> MenuAction where T: FnMut(&Window) + 'static {
+        MenuAction::Callback(Box::new(func))
+    }
+    pub fn lazy_menu<T>(func: T) -> MenuAction where T: FnMut(&Menu, &Window) + 'static {
+        MenuAction::LazyMenu(Box::new(func))
+    }
+}
+type RadioHandler<T> = Box<dyn FnMut(T, &Window)>;
+pub struct RadioGroup<T> {
+    items: Vec<(String, T)>,
+    selected: Option<T>,
+    handler: Option<RadioHandler<T>>,
+}
+impl<T> Default for RadioGroup<T> where T: Clone + PartialEq + 'static {
+    fn default() -> RadioGroup<T> {
+        RadioGroup::new()
+    }
+}
+impl<T> RadioGroup<T> where T: Clone + PartialEq + 'static {
+    pub fn new() -> RadioGroup<T> {
+        RadioGroup {
+            items: Vec::new(),
+            selected: None,
+            handler: None,
+        }
+    }
+    pub fn item(mut self, label: &str, value: T) -> RadioGroup<T> {
+        self.items.push((label.to_owned(), value));
+        self
+    }
+    pub fn selected(mut self, value: T) -> RadioGroup<T> {
+        self.selected = Some(value);
+        self
+    }
+    // Called with the value of the newly selected item.
+    pub fn handler<F>(
+        mut self, handler: F,
+    ) -> RadioGroup<T> where F: FnMut(T, &Window) + 'static {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+}
+// Applied with `Menu::set_style`. MNS_NOTIFYBYPOS is deliberately left out, as commands are
+// routed by id.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MenuStyle {
+    // Shows item bitmaps in the check mark column instead of beside it.
+    pub check_or_bitmap: bool,
+    pub no_check: bool,
+    pub auto_dismiss: bool,
+    pub max_height: Option<u32>,
+    pub apply_to_submenus: bool,
+}
+// Everything owned on behalf of the items of a menu and all of its submenus. Windows destroys
+// submenus along with their parent, so it all has to live exactly as long as the top menu does.
+#[derive(Default)]
+struct MenuResources {
+    // Command ids allocated for callbacks.
+    commands: Vec<u16>,
+    bitmaps: Vec<Bitmap>,
+    brushes: Vec<Brush>,
+    // Tokens identifying the closures of owner drawn items.
+    owner_drawn: Vec<usize>,
+    // Submenus registered to be filled lazily.
+    lazy_menus: Vec<usize>,
+}
+impl MenuResources {
+    fn append(&mut self, mut other: MenuResources) {
+        self.commands.append(&mut other.commands);
+        self.bitmaps.append(&mut other.bitmaps);
+        self.brushes.append(&mut other.brushes);
+        self.owner_drawn.append(&mut other.owner_drawn);
+        self.lazy_menus.append(&mut other.lazy_menus);
+    }
+    // Splits off the resources used by the given items.
+    fn split_off(&mut self, used: &ItemResources) -> MenuResources {
+        let mut other = MenuResources::default();
+        let (taken, kept) = self.commands.drain(..).partition(|id| used.commands.contains(id));
+        other.commands = taken;
+        self.commands = kept;
+        let (taken, kept) = self.bitmaps.drain(..)
+            .partition(|b| used.bitmaps.contains(&b.as_raw()));
+        other.bitmaps = taken;
+        self.bitmaps = kept;
+        let (taken, kept) = self.owner_drawn.drain(..)
+            .partition(|token| used.owner_drawn.contains(token));
+        other.owner_drawn = taken;
+        self.owner_drawn = kept;
+        let (taken, kept) = self.lazy_menus.drain(..)
+            .partition(|menu| used.submenus.contains(&(*menu as HMENU)));
+        other.lazy_menus = taken;
+        self.lazy_menus = kept;
+        other
+    }
+}
+impl Drop for MenuResources {
+    fn drop(&mut self) {
+        for &id in &self.commands {
+            command::free(id);
+        }
+        for &token in &self.owner_drawn {
+            ownerdraw::unregister(token);
+        }
+        for &menu in &self.lazy_menus {
+            unregister_lazy(menu);
+        }
+    }
+}
+// What the items of a menu refer to, as found by looking at the live menu.
+#[derive(Default)]
+struct ItemResources {
+    commands: Vec<u16>,
+    bitmaps: Vec<HBITMAP>,
+    owner_drawn: Vec<usize>,
+    submenus: Vec<HMENU>,
+}
+// A lazy submenu keeps the resources of its items to itself, as they are replaced every time it
+// is filled, rather than handing them to the top menu.
+struct LazyMenu {
+    populate: LazyHandler,
+    resources: MenuResources,
+}
+thread_local!{
+    static LAZY_MENUS: RefCell<HashMap<usize, LazyMenu>> = RefCell::new(HashMap::new());
+}
+fn register_lazy(menu: HMENU, populate: LazyHandler) {
+    let lazy = LazyMenu {
+        populate: populate,
+        resources: MenuResources::default(),
+    };
+    LAZY_MENUS.with(|map| map.borrow_mut().insert(menu as usize, lazy));
+}
+fn unregister_lazy(menu: usize) {
+    // Dropped outside of the borrow, as its resources may unregister nested lazy submenus.
+    let lazy = LAZY_MENUS.with(|map| map.borrow_mut().remove(&menu));
+    drop(lazy);
+}
+// Called by the window for WM_INITMENUPOPUP, which is also sent for submenus that aren't lazy.
+pub(crate) fn populate_lazy(menu: HMENU, window: &Window) {
+    // Taken out of the table while running, so the closure is free to add nested lazy submenus.
+    let mut lazy = match LAZY_MENUS.with(|map| map.borrow_mut().remove(&(menu as usize))) {
+        Some(lazy) => lazy,
+        None => return,
+    };
+    let submenu = Menu {
+        handle: menu,
+        resources: RefCell::new(take(&mut lazy.resources)),
+    };
+    (lazy.populate)(&submenu, window);
+    lazy.resources = submenu.take_resources();
+    forget(submenu);
+    LAZY_MENUS.with(|map| map.borrow_mut().insert(menu as usize, lazy));
 }
 pub struct Menu {
     handle: HMENU,
+    resources: RefCell<MenuResources>,
 }
 impl Menu {
     pub unsafe fn from_raw(handle: HMENU) -> Menu {
         Menu {
             handle: handle,
+            resources: RefCell::new(MenuResources::default()),
         }
     }
     pub fn as_raw(&self) -> HMENU {
         self.handle
     }
+    // Any callbacks attached to the menu, including those drawing owner drawn items and filling
+    // lazy submenus, are released, as they can no longer be freed along with the menu. Bitmaps and
+    // brushes are leaked instead, as the menu still draws with them.
     pub fn into_raw(self) -> HMENU {
+        let mut resources = self.take_resources();
+        forget(take(&mut resources.bitmaps));
+        forget(take(&mut resources.brushes));
         let handle = self.handle;
         forget(self);
         handle
     }
-    pub fn append_string<'a>(
+    fn take_resources(&self) -> MenuResources {
+        take(&mut *self.resources.borrow_mut())
+    }
+    // Returns a reference to the new item. Items with submenus are given an id of their own too,
+    // which never shows up as a command, so that the reference stays valid as items are inserted
+    // and removed around it. The label is in the syntax described in `mnemonic`, so text
+    // from elsewhere should go through `mnemonic::escape` first.
+    pub fn append_string(
         &self, string: &str, action: MenuAction, status: MenuStatus, check: MenuCheck,
-    ) -> Result<(), Error> {
-        let mut flags = 0;
-        let action = match action {
-            MenuAction::Id(n) => n as usize,
+    ) -> Result<MenuItem, Error> {
+        self.add_string(None, string, action, status, check, None)
+    }
+    // Inserts the new item before the given item, in the submenu holding it when given an id.
+    pub fn insert_string(
+        &self, before: MenuItem, string: &str, action: MenuAction, status: MenuStatus,
+        check: MenuCheck,
+    ) -> Result<MenuItem, Error> {
+        self.add_string(Some(before), string, action, status, check, None)
+    }
+    // Appends an item that is measured and painted by the given closures. The label is still used
+    // for keyboard access and accessibility.
+    pub fn append_owner_drawn(
+        &self, label: &str, action: MenuAction, status: MenuStatus, check: MenuCheck,
+        item: OwnerDrawn,
+    ) -> Result<MenuItem, Error> {
+        self.add_string(None, label, action, status, check, Some(item))
+    }
+    fn add_string(
+        &self, before: Option<MenuItem>, string: &str, action: MenuAction, status: MenuStatus,
+        check: MenuCheck, owner_drawn: Option<OwnerDrawn>,
+    ) -> Result<MenuItem, Error> {
+        if let MenuAction::Id(id) = action {
+            if command::is_automatic(id) {
+                return Err(Error::from_raw(ERROR_INVALID_PARAMETER));
+            }
+        }
+        let (menu, position) = match before {
+            Some(before) => self.locate(before)?,
+            None => (self.handle, self.item_count()?),
+        };
+        let string = string.to_wide_null();
+        let mut info = empty_item_info();
+        info.fMask = MIIM_FTYPE | MIIM_STATE | MIIM_STRING;
+        info.fType = MFT_STRING | check.as_type();
+        info.fState = status.as_flags() | check.as_flags();
+        info.dwTypeData = string.as_ptr() as *mut u16;
+        let token = owner_drawn.map(ownerdraw::register);
+        if let Some(token) = token {
+            info.fMask |= MIIM_DATA;
+            info.fType |= MFT_OWNERDRAW;
+            info.dwItemData = token;
+        }
+        let mut allocated = None;
+        let mut child = None;
+        let mut lazy = None;
+        match action {
+            MenuAction::Id(n) => {
+                info.fMask |= MIIM_ID;
+                info.wID = n as u32;
+            },
             MenuAction::ChildMenu(menu) => {
-                flags |= MF_POPUP;
-                menu.into_inner().into_raw() as usize
+                let menu = menu.into_inner();
+                info.fMask |= MIIM_SUBMENU;
+                info.hSubMenu = menu.as_raw();
+                child = Some(menu);
+            },
+            MenuAction::LazyMenu(func) => {
+                let menu = match PopupMenu::new() {
+                    Ok(menu) => menu.into_inner(),
+                    Err(err) => {
+                        if let Some(token) = token {
+                            ownerdraw::unregister(token);
+                        }
+                        return Err(err);
+                    },
+                };
+                register_lazy(menu.as_raw(), func);
+                lazy = Some(menu.as_raw() as usize);
+                info.fMask |= MIIM_SUBMENU;
+                info.hSubMenu = menu.as_raw();
+                child = Some(menu);
+            },
+            MenuAction::Callback(func) => {
+                let id = match command::allocate(func) {
+                    Ok(id) => id,
+                    Err(err) => {
+                        if let Some(token) = token {
+                            ownerdraw::unregister(token);
+                        }
+                        return Err(err);
+                    },
+                };
+                allocated = Some(id);
+                info.fMask |= MIIM_ID;
+                info.wID = id as u32;
             },
-        };
-        match status {
-            MenuStatus::Enabled => flags |= MF_ENABLED,
-            MenuStatus::Disabled => flags |= MF_DISABLED,
-            MenuStatus::Grayed => flags |= MF_GRAYED,
         }
-        match check {
-            MenuCheck::Checked => flags |= MF_CHECKED,
-            MenuCheck::Unchecked => flags |= MF_UNCHECKED,
+        if child.is_some() {
+            // Choosing an item with a submenu only opens it, so the id is never invoked.
+            match command::allocate(Box::new(|_| ())) {
+                Ok(id) => {
+                    allocated = Some(id);
+                    info.fMask |= MIIM_ID;
+                    info.wID = id as u32;
+                },
+                Err(err) => {
+                    if let Some(token) = token {
+                        ownerdraw::unregister(token);
+                    }
+                    if let Some(menu) = lazy {
+                        unregister_lazy(menu);
+                    }
+                    return Err(err);
+                },
+            }
         }
-        if unsafe { AppendMenuW(
-            self.handle, flags | MF_STRING, action, string.to_wide_null().as_ptr(),
+        if unsafe { InsertMenuItemW(menu, position, TRUE, &info) } == 0 {
+            let err = Error::get_last_error();
+            if let Some(id) = allocated {
+                command::free(id);
+            }
+            if let Some(token) = token {
+                ownerdraw::unregister(token);
+            }
+            if let Some(menu) = lazy {
+                unregister_lazy(menu);
+            }
+            return Err(err);
+        }
+        if let Some(id) = allocated {
+            self.resources.borrow_mut().commands.push(id);
+        }
+        if let Some(token) = token {
+            self.resources.borrow_mut().owner_drawn.push(token);
+        }
+        if let Some(menu) = lazy {
+            self.resources.borrow_mut().lazy_menus.push(menu);
+        }
+        if let Some(menu) = child {
+            let resources = menu.take_resources();
+            self.resources.borrow_mut().append(resources);
+            forget(menu);
+        }
+        Ok(MenuItem::Id(info.wID as u16))
+    }
+    // Appends one item per value in the group, drawn with a radio bullet instead of a check mark.
+    // Choosing an item moves the bullet to it before the group handler is called. An empty group
+    // is an invalid parameter. Either every item is appended or none is.
+    pub fn append_radio_group<T>(&self, group: RadioGroup<T>) -> Result<Vec<MenuItem>, Error>
+        where T: Clone + PartialEq + 'static
+    {
+        if group.items.is_empty() {
+            return Err(Error::from_raw(ERROR_INVALID_PARAMETER));
+        }
+        let handler = Rc::new(RefCell::new(group.handler.unwrap_or_else(|| Box::new(|_, _| ()))));
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        let menu = self.handle;
+        let mut items = Vec::new();
+        for (i, (label, value)) in group.items.into_iter().enumerate() {
+            let check = if group.selected.as_ref() == Some(&value) {
+                MenuCheck::Radio
+            } else {
+                MenuCheck::RadioUnchecked
+            };
+            let handler = handler.clone();
+            let group_ids = ids.clone();
+            let action = MenuAction::callback(move |window| {
+                {
+                    let ids = group_ids.borrow();
+                    let (first, last) = (ids[0] as u32, ids[ids.len() - 1] as u32);
+                    unsafe { CheckMenuRadioItem(menu, first, last, ids[i] as u32, MF_BYCOMMAND) };
+                }
+                (*handler.borrow_mut())(value.clone(), window);
+            });
+            let item = match self.append_string(&label, action, MenuStatus::Enabled, check) {
+                Ok(item) => item,
+                Err(err) => {
+                    for &item in items.iter().rev() {
+                        let _ = self.delete(item);
+                    }
+                    return Err(err);
+                },
+            };
+            ids.borrow_mut().push(item.as_raw() as u16);
+            items.push(item);
+        }
+        Ok(items)
+    }
+    // Checks one item in the range with a radio bullet and unchecks the rest. The items must all
+    // be in the same menu, or this fails with ERROR_INVALID_PARAMETER.
+    pub fn check_radio(
+        &self, first: MenuItem, last: MenuItem, selected: MenuItem,
+    ) -> Result<(), Error> {
+        let (menu, first) = self.locate(first)?;
+        let (last_menu, last) = self.locate(last)?;
+        let (selected_menu, selected) = self.locate(selected)?;
+        if last_menu != menu || selected_menu != menu {
+            return Err(Error::from_raw(ERROR_INVALID_PARAMETER));
+        }
+        if unsafe { CheckMenuRadioItem(menu, first, last, selected, MF_BYPOSITION) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    // Replaces the default check mark images. The bitmaps are not destroyed with the menu, so
+    // they must outlive it.
+    pub fn set_check_bitmaps(
+        &self, item: MenuItem, checked: Option<HBITMAP>, unchecked: Option<HBITMAP>,
+    ) -> Result<(), Error> {
+        if unsafe { SetMenuItemBitmaps(
+            self.handle, item.as_raw(), item.as_flags(),
+            unchecked.unwrap_or(null_mut()), checked.unwrap_or(null_mut()),
         )} == 0 {
             return Err(Error::get_last_error());
         }
@@ -74,7 +512,299 @@ impl Menu {
         }
         Ok(())
     }
+    pub fn insert_separator(&self, before: MenuItem) -> Result<(), Error> {
+        let mut info = empty_item_info();
+        info.fMask = MIIM_FTYPE;
+        info.fType = MFT_SEPARATOR;
+        let (menu, position) = self.locate(before)?;
+        if unsafe { InsertMenuItemW(menu, position, TRUE, &info) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    pub fn item_count(&self) -> Result<u32, Error> {
+        let count = unsafe { GetMenuItemCount(self.handle) };
+        if count < 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(count as u32)
+    }
+    // The menu holding the item and its position there. Positions are in this menu, while ids are
+    // looked up in the submenus as well, the way Windows does.
+    fn locate(&self, item: MenuItem) -> Result<(HMENU, u32), Error> {
+        match item {
+            MenuItem::Position(n) => Ok((self.handle, n)),
+            MenuItem::Id(id) => {
+                find_item(self.handle, id).ok_or(Error::from_raw(ERROR_MENU_ITEM_NOT_FOUND))
+            },
+        }
+    }
+    // Returns the previous check state.
+    pub fn set_check(&self, item: MenuItem, check: MenuCheck) -> Result<MenuCheck, Error> {
+        let mut info = empty_item_info();
+        info.fMask = MIIM_FTYPE | MIIM_STATE;
+        self.get_item_info(item, &mut info)?;
+        let prev = MenuCheck::from_raw(info.fType, info.fState);
+        info.fType = (info.fType & !MFT_RADIOCHECK) | check.as_type();
+        info.fState = (info.fState & !MF_CHECKED) | check.as_flags();
+        if unsafe {
+            SetMenuItemInfoW(self.handle, item.as_raw(), item.by_position(), &info)
+        } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(prev)
+    }
+    // Returns the previous status.
+    pub fn set_status(&self, item: MenuItem, status: MenuStatus) -> Result<MenuStatus, Error> {
+        let flags = item.as_flags() | status.as_flags();
+        let prev = unsafe { EnableMenuItem(self.handle, item.as_raw(), flags) };
+        if prev == -1 {
+            return Err(Error::from_raw(ERROR_MENU_ITEM_NOT_FOUND));
+        }
+        Ok(MenuStatus::from_flags(prev as u32))
+    }
+    pub fn set_label(&self, item: MenuItem, string: &str) -> Result<(), Error> {
+        let string = string.to_wide_null();
+        let mut info = empty_item_info();
+        info.fMask = MIIM_STRING;
+        info.dwTypeData = string.as_ptr() as *mut u16;
+        if unsafe {
+            SetMenuItemInfoW(self.handle, item.as_raw(), item.by_position(), &info)
+        } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    pub fn item_info(&self, item: MenuItem) -> Result<MenuItemInfo, Error> {
+        let mut info = empty_item_info();
+        info.fMask = MIIM_FTYPE | MIIM_STATE | MIIM_ID | MIIM_SUBMENU | MIIM_STRING;
+        self.get_item_info(item, &mut info)?;
+        let text = if info.cch == 0 || info.fType & MFT_SEPARATOR != 0 { None } else {
+            let mut text = vec![0u16; info.cch as usize + 1];
+            info.dwTypeData = text.as_mut_ptr();
+            info.cch += 1;
+            self.get_item_info(item, &mut info)?;
+            text.truncate(info.cch as usize);
+            Some(String::from_utf16_lossy(&text))
+        };
+        Ok(MenuItemInfo {
+            id: if info.hSubMenu.is_null() || info.wID != 0 { Some(info.wID as u16) } else { None },
+            text: text,
+            status: MenuStatus::from_flags(info.fState),
+            check: MenuCheck::from_raw(info.fType, info.fState),
+            separator: info.fType & MFT_SEPARATOR != 0,
+            submenu: if info.hSubMenu.is_null() { None } else { Some(info.hSubMenu) },
+        })
+    }
+    fn get_item_info(&self, item: MenuItem, info: &mut MENUITEMINFOW) -> Result<(), Error> {
+        if unsafe {
+            GetMenuItemInfoW(self.handle, item.as_raw(), item.by_position(), info)
+        } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    // Detaches the item from the menu. If it has a submenu, ownership of it is returned.
+    pub fn remove(&self, item: MenuItem) -> Result<Option<PopupMenu>, Error> {
+        let (submenu, used) = self.item_resources(item)?;
+        if unsafe { RemoveMenu(self.handle, item.as_raw(), item.as_flags()) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        let resources = self.resources.borrow_mut().split_off(&used);
+        if submenu.is_null() {
+            return Ok(None);
+        }
+        let menu = unsafe { Menu::from_raw(submenu) };
+        *menu.resources.borrow_mut() = resources;
+        Ok(Some(PopupMenu(menu)))
+    }
+    // Removes the item from the menu, destroying its submenu if it has one.
+    pub fn delete(&self, item: MenuItem) -> Result<(), Error> {
+        let (_, used) = self.item_resources(item)?;
+        if unsafe { DeleteMenu(self.handle, item.as_raw(), item.as_flags()) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        self.resources.borrow_mut().split_off(&used);
+        Ok(())
+    }
+    // Deletes every item, which is usually the first thing done when filling a lazy submenu.
+    pub fn clear(&self) -> Result<(), Error> {
+        for position in (0..self.item_count()?).rev() {
+            self.delete(MenuItem::Position(position))?;
+        }
+        Ok(())
+    }
+    // The submenu of an item, along with the resources used by the item and its submenu.
+    fn item_resources(&self, item: MenuItem) -> Result<(HMENU, ItemResources), Error> {
+        let (menu, position) = self.locate(item)?;
+        let mut resources = ItemResources::default();
+        collect_resources(menu, Some(position), &mut resources);
+        let submenu = unsafe { GetSubMenu(menu, position as i32) };
+        Ok((submenu, resources))
+    }
+    // The bitmap is kept alive for as long as the menu is.
+    pub fn set_item_bitmap(&self, item: MenuItem, bitmap: Bitmap) -> Result<(), Error> {
+        let mut info = empty_item_info();
+        info.fMask = MIIM_BITMAP;
+        self.get_item_info(item, &mut info)?;
+        let old = info.hbmpItem;
+        info.hbmpItem = bitmap.as_raw();
+        if unsafe {
+            SetMenuItemInfoW(self.handle, item.as_raw(), item.by_position(), &info)
+        } == 0 {
+            return Err(Error::get_last_error());
+        }
+        let mut resources = self.resources.borrow_mut();
+        resources.split_off(&ItemResources { bitmaps: vec![old], ..ItemResources::default() });
+        resources.bitmaps.push(bitmap);
+        Ok(())
+    }
+    // Shows the icon beside the item at the small icon size.
+    pub fn set_item_icon(&self, item: MenuItem, icon: &Icon) -> Result<(), Error> {
+        let width = unsafe { GetSystemMetrics(SM_CXSMICON) } as u32;
+        let height = unsafe { GetSystemMetrics(SM_CYSMICON) } as u32;
+        self.set_item_bitmap(item, Bitmap::from_icon(icon, width, height)?)
+    }
+    pub fn set_style(&self, style: MenuStyle) -> Result<(), Error> {
+        let mut info = empty_menu_info();
+        info.fMask = MIM_STYLE;
+        if style.check_or_bitmap { info.dwStyle |= MNS_CHECKORBMP }
+        if style.no_check { info.dwStyle |= MNS_NOCHECK }
+        if style.auto_dismiss { info.dwStyle |= MNS_AUTODISMISS }
+        if let Some(height) = style.max_height {
+            info.fMask |= MIM_MAXHEIGHT;
+            info.cyMax = height;
+        }
+        if style.apply_to_submenus { info.fMask |= MIM_APPLYTOSUBMENUS }
+        self.set_menu_info(&info)
+    }
+    // The brush is kept alive for as long as the menu is.
+    pub fn set_background(&self, brush: Brush) -> Result<(), Error> {
+        let mut info = empty_menu_info();
+        info.fMask = MIM_BACKGROUND;
+        info.hbrBack = brush.as_raw();
+        self.set_menu_info(&info)?;
+        self.resources.borrow_mut().brushes.push(brush);
+        Ok(())
+    }
+    // Shows the shortcut for each item bound to a command in the table, right aligned after a tab,
+    // replacing any shortcut text the item already had.
+    pub fn show_shortcuts(&self, table: &AcceleratorTable) -> Result<(), Error> {
+        for i in 0..self.item_count()? {
+            let item = MenuItem::Position(i);
+            let info = self.item_info(item)?;
+            if info.submenu.is_some() {
+                self.with_submenu(i, |submenu| submenu.show_shortcuts(table))??;
+            }
+            let (id, text) = match (info.id, info.text) {
+                (Some(id), Some(text)) => (id, text),
+                _ => continue,
+            };
+            let label = text.split('\t').next().unwrap_or("");
+            let new = match table.shortcut_for(id) {
+                Some(shortcut) => format!("{}\t{}", label, shortcut),
+                None => label.to_owned(),
+            };
+            if new != text {
+                self.set_label(item, &new)?;
+            }
+        }
+        Ok(())
+    }
+    // Shows the item in bold as the one chosen by default, or clears the default with None.
+    pub fn set_default_item(&self, item: Option<MenuItem>) -> Result<(), Error> {
+        let (raw, by_position) = match item {
+            Some(item) => (item.as_raw(), item.by_position()),
+            None => (!0, FALSE),
+        };
+        if unsafe { SetMenuDefaultItem(self.handle, raw, by_position as u32) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    // Borrows the submenu at the position. The resources of the whole tree are tracked by the top
+    // menu, so they are lent to the submenu while `func` runs and handed back afterwards, which
+    // lets items be added and removed through it like through the top menu.
+    pub(crate) fn with_submenu<T, R>(&self, position: u32, func: T) -> Result<R, Error>
+        where T: FnOnce(&Menu) -> R
+    {
+        let handle = unsafe { GetSubMenu(self.handle, position as i32) };
+        if handle.is_null() {
+            return Err(Error::from_raw(ERROR_MENU_ITEM_NOT_FOUND));
+        }
+        let submenu = Menu {
+            handle: handle,
+            resources: RefCell::new(self.take_resources()),
+        };
+        let ret = func(&submenu);
+        let resources = submenu.take_resources();
+        forget(submenu);
+        self.resources.borrow_mut().append(resources);
+        Ok(ret)
+    }
+    fn set_menu_info(&self, info: &MENUINFO) -> Result<(), Error> {
+        if unsafe { SetMenuInfo(self.handle, info) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+}
+// The menu holding the item with the id and its position there, searching submenus depth first.
+fn find_item(menu: HMENU, id: u16) -> Option<(HMENU, u32)> {
+    for i in 0..unsafe { GetMenuItemCount(menu) }.max(0) as u32 {
+        let mut info = empty_item_info();
+        info.fMask = MIIM_ID | MIIM_SUBMENU;
+        if unsafe { GetMenuItemInfoW(menu, i, TRUE, &mut info) } == 0 {
+            continue;
+        }
+        if info.wID == id as u32 {
+            return Some((menu, i));
+        }
+        if !info.hSubMenu.is_null() {
+            if let Some(found) = find_item(info.hSubMenu, id) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+// Collects the resources used by either one item or every item in a menu, recursing into submenus.
+fn collect_resources(menu: HMENU, position: Option<u32>, resources: &mut ItemResources) {
+    let positions = match position {
+        Some(n) => n..n + 1,
+        None => 0..unsafe { GetMenuItemCount(menu) }.max(0) as u32,
+    };
+    for i in positions {
+        let mut info = empty_item_info();
+        info.fMask = MIIM_ID | MIIM_BITMAP | MIIM_SUBMENU | MIIM_DATA;
+        if unsafe { GetMenuItemInfoW(menu, i, TRUE, &mut info) } == 0 {
+            continue;
+        }
+        if !info.hbmpItem.is_null() {
+            resources.bitmaps.push(info.hbmpItem);
+        }
+        if info.dwItemData != 0 {
+            resources.owner_drawn.push(info.dwItemData);
+        }
+        // Items with a submenu may have an id allocated for them as well.
+        resources.commands.push(info.wID as u16);
+        if !info.hSubMenu.is_null() {
+            resources.submenus.push(info.hSubMenu);
+            collect_resources(info.hSubMenu, None, resources);
+        }
+    }
+}
+fn empty_menu_info() -> MENUINFO {
+    let mut info: MENUINFO = unsafe { zeroed() };
+    info.cbSize = size_of::<MENUINFO>() as u32;
+    info
 }
+fn empty_item_info() -> MENUITEMINFOW {
+    let mut info: MENUITEMINFOW = unsafe { zeroed() };
+    info.cbSize = size_of::<MENUITEMINFOW>() as u32;
+    info
+}
+// The resources are dropped after the menu is destroyed, which is the order they need to go in.
 impl Drop for Menu {
     fn drop(&mut self) {
         if unsafe { DestroyMenu(self.handle) } == 0 {
@@ -82,6 +812,82 @@ impl Drop for Menu {
         }
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HorizontalAlign {
+    Left,
+    Center,
+    Right,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerticalAlign {
+    Top,
+    Center,
+    Bottom,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuAnimation {
+    // Whatever the system settings say.
+    Default,
+    None,
+    LeftToRight,
+    RightToLeft,
+    TopToBottom,
+    BottomToTop,
+}
+// How a popup menu is positioned relative to the point it is shown at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PopupOptions {
+    pub horizontal: HorizontalAlign,
+    pub vertical: VerticalAlign,
+    pub animation: MenuAnimation,
+    // Lets the right mouse button choose items as well as the left one.
+    pub right_button: bool,
+    // The menu is placed so that it doesn't cover this area, such as the taskbar.
+    pub exclude: Option<Rect>,
+    // When avoiding `exclude`, prefer moving the menu vertically rather than horizontally.
+    pub prefer_vertical: bool,
+    // Rewrites the labels to show the shortcuts from the accelerator table of the window, the way
+    // the menu bar always does. On by default.
+    pub show_shortcuts: bool,
+}
+impl PopupOptions {
+    fn as_flags(&self) -> u32 {
+        let mut flags = match self.horizontal {
+            HorizontalAlign::Left => TPM_LEFTALIGN,
+            HorizontalAlign::Center => TPM_CENTERALIGN,
+            HorizontalAlign::Right => TPM_RIGHTALIGN,
+        };
+        flags |= match self.vertical {
+            VerticalAlign::Top => TPM_TOPALIGN,
+            VerticalAlign::Center => TPM_VCENTERALIGN,
+            VerticalAlign::Bottom => TPM_BOTTOMALIGN,
+        };
+        flags |= match self.animation {
+            MenuAnimation::Default => 0,
+            MenuAnimation::None => TPM_NOANIMATION,
+            MenuAnimation::LeftToRight => TPM_HORPOSANIMATION,
+            MenuAnimation::RightToLeft => TPM_HORNEGANIMATION,
+            MenuAnimation::TopToBottom => TPM_VERPOSANIMATION,
+            MenuAnimation::BottomToTop => TPM_VERNEGANIMATION,
+        };
+        if self.right_button { flags |= TPM_RIGHTBUTTON }
+        if self.prefer_vertical { flags |= TPM_VERTICAL }
+        flags
+    }
+}
+impl Default for PopupOptions {
+    fn default() -> PopupOptions {
+        PopupOptions {
+            horizontal: HorizontalAlign::Left,
+            vertical: VerticalAlign::Top,
+            animation: MenuAnimation::Default,
+            right_button: false,
+            exclude: None,
+            prefer_vertical: false,
+            show_shortcuts: true,
+        }
+    }
+}
 pub struct PopupMenu(Menu);
 impl PopupMenu {
     pub fn new() -> Result<PopupMenu, Error> {
@@ -93,17 +899,70 @@ impl PopupMenu {
     }
     pub fn display<T>(
         &self, window: &Window, x: i32, y: i32, func: T,
-    ) -> Result<(), Error> where T: FnMut(u16, &Window) + 'static {
-        unsafe {
-            if SetForegroundWindow(window.as_raw()) == 0 {
-                return Err(Error::get_last_error());
+    ) -> Result<(), Error> where T: FnMut(u16, &Window) {
+        self.display_with(window, x, y, PopupOptions::default(), func)
+    }
+    // Like `select`, but passes the chosen command id to `func` when the item has no callback.
+    pub fn display_with<T>(
+        &self, window: &Window, x: i32, y: i32, options: PopupOptions, mut func: T,
+    ) -> Result<(), Error> where T: FnMut(u16, &Window) {
+        if let Some(MenuItem::Id(id)) = self.track(window, x, y, options)? {
+            if !command::invoke(id, window) {
+                func(id, window);
             }
-            window.set_menu_handler(Box::new(func));
-            if TrackPopupMenuEx(self.handle, 0, x, y, window.as_raw(), null_mut()) == 0 {
-                return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    // Shows the menu and waits until it is dismissed, returning the item that was chosen if any.
+    // Callbacks attached to the item are run before returning.
+    pub fn select(
+        &self, window: &Window, x: i32, y: i32, options: PopupOptions,
+    ) -> Result<Option<MenuItem>, Error> {
+        let item = self.track(window, x, y, options)?;
+        if let Some(MenuItem::Id(id)) = item {
+            command::invoke(id, window);
+        }
+        Ok(item)
+    }
+    fn track(
+        &self, window: &Window, x: i32, y: i32, options: PopupOptions,
+    ) -> Result<Option<MenuItem>, Error> {
+        if options.show_shortcuts {
+            window.show_shortcuts_in(self)?;
+        }
+        let hwnd = window.as_raw();
+        // The menu won't go away when clicking elsewhere unless the window is in the foreground.
+        if unsafe { SetForegroundWindow(hwnd) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        let mut params: TPMPARAMS = unsafe { zeroed() };
+        params.cbSize = size_of::<TPMPARAMS>() as u32;
+        let params = match options.exclude {
+            Some(r) => {
+                params.rcExclude = RECT {
+                    left: r.left, top: r.top, right: r.right, bottom: r.bottom,
+                };
+                &mut params as LPTPMPARAMS
+            },
+            None => null_mut(),
+        };
+        // The command is returned directly instead of being posted as WM_COMMAND, so that nothing
+        // is left waiting for a command that never arrives when the menu is dismissed. The other
+        // notifications are still sent, as lazy submenus depend on them.
+        Error::clear();
+        let flags = options.as_flags() | TPM_RETURNCMD;
+        let id = unsafe { TrackPopupMenuEx(self.handle, flags, x, y, hwnd, params) };
+        let err = Error::get_last_error();
+        // Without a message being processed after the menu closes, the next attempt to show it
+        // from a notification icon fails to dismiss properly.
+        unsafe { PostMessageW(hwnd, WM_NULL, 0, 0) };
+        if id == 0 {
+            if err.as_raw() != 0 {
+                return Err(err);
             }
-            Ok(())
+            return Ok(None);
         }
+        Ok(Some(MenuItem::Id(id as u16)))
     }
     fn into_inner(self) -> Menu {
         self.0
@@ -121,6 +980,15 @@ impl DerefMut for PopupMenu {
     }
 }
 pub struct MenuBar(Menu);
+impl MenuBar {
+    pub fn new() -> Result<MenuBar, Error> {
+        let menu = unsafe { CreateMenu() };
+        if menu.is_null() {
+            return Err(Error::get_last_error());
+        }
+        Ok(MenuBar(unsafe { Menu::from_raw(menu) }))
+    }
+}
 impl Deref for MenuBar {
     type Target = Menu;
     fn deref(&self) -> &Menu {
@@ -0,0 +1,545 @@
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::fmt::{self, Write};
+
+use mnemonic::{LabelProblem, validate_level};
+
+// The menu state shared with `menu`, along with a retained model of a whole menu tree which can be
+// diffed against a newer version of itself.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "definitions", derive(Deserialize))]
+#[cfg_attr(feature = "definitions", serde(rename_all = "snake_case"))]
+pub enum MenuStatus {
+    Enabled,
+    Disabled,
+    Grayed,
+}
+// The radio variants draw a bullet instead of a check mark, and only need to be kept exclusive by
+// hand when not using `Menu::append_radio_group`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "definitions", derive(Deserialize))]
+#[cfg_attr(feature = "definitions", serde(rename_all = "snake_case"))]
+pub enum MenuCheck {
+    Checked,
+    Unchecked,
+    Radio,
+    RadioUnchecked,
+}
+// Items are matched between versions of a model by their key, which must be unique within one
+// level of the menu.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModelItem {
+    Command {
+        key: String,
+        label: String,
+        id: u16,
+        status: MenuStatus,
+        check: MenuCheck,
+    },
+    Separator {
+        key: String,
+    },
+    Submenu {
+        key: String,
+        label: String,
+        status: MenuStatus,
+        items: Vec<ModelItem>,
+    },
+}
+impl ModelItem {
+    pub fn key(&self) -> &str {
+        match *self {
+            ModelItem::Command { ref key, .. } => key,
+            ModelItem::Separator { ref key } => key,
+            ModelItem::Submenu { ref key, .. } => key,
+        }
+    }
+    // Whether the live item for `self` can be updated in place to become `other`. Changing the
+    // kind of item or its command id requires replacing it.
+    fn matches(&self, other: &ModelItem) -> bool {
+        match (self, other) {
+            (&ModelItem::Command { key: ref a, id: x, .. },
+                &ModelItem::Command { key: ref b, id: y, .. }) => a == b && x == y,
+            (&ModelItem::Separator { key: ref a }, &ModelItem::Separator { key: ref b }) => a == b,
+            (&ModelItem::Submenu { key: ref a, .. }, &ModelItem::Submenu { key: ref b, .. }) => {
+                a == b
+            },
+            _ => false,
+        }
+    }
+}
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MenuModel {
+    pub items: Vec<ModelItem>,
+}
+// A single change to a live menu. `path` holds the positions of the submenus leading to the menu
+// being changed, starting from the top, and is empty for the top menu itself. Patches must be
+// applied in order, as positions refer to the menu as left by the previous patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Patch {
+    Remove {
+        path: Vec<u32>,
+        position: u32,
+    },
+    Insert {
+        path: Vec<u32>,
+        position: u32,
+        item: ModelItem,
+    },
+    Modify {
+        path: Vec<u32>,
+        position: u32,
+        label: Option<String>,
+        status: Option<MenuStatus>,
+        check: Option<MenuCheck>,
+    },
+}
+// Two items on the same level share a key, so the diff couldn't tell which is which. `path` is
+// the path of the level, like in `Patch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateKey {
+    pub path: Vec<u32>,
+    pub key: String,
+}
+impl fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path: Vec<String> = self.path.iter().map(|p| p.to_string()).collect();
+        write!(f, "key {:?} is used more than once in menu [{}]", self.key, path.join("."))
+    }
+}
+impl StdError for DuplicateKey {}
+impl MenuModel {
+    pub fn new() -> MenuModel {
+        MenuModel::default()
+    }
+    // The patches that turn a menu built from `self` into one built from `new`. Fails if either
+    // model has duplicate keys.
+    pub fn diff(&self, new: &MenuModel) -> Result<Vec<Patch>, DuplicateKey> {
+        self.check_keys()?;
+        new.check_keys()?;
+        let mut patches = Vec::new();
+        diff_level(&self.items, &new.items, &mut Vec::new(), &mut patches);
+        Ok(patches)
+    }
+    // Checks that keys are unique within every level of the tree.
+    pub fn check_keys(&self) -> Result<(), DuplicateKey> {
+        check_level_keys(&self.items, &mut Vec::new())
+    }
+    // A plain text rendering of the tree for snapshot tests, one item per line:
+    //
+    //     Pause #1
+    //     [x] Start with Windows #2
+    //     ---
+    //     Mode >
+    //       (*) Fast #3
+    //       ( ) Quiet #4 (grayed)
+    pub fn outline(&self) -> String {
+        let mut out = String::new();
+        outline_level(&self.items, 0, &mut out);
+        out
+    }
+    // Checks the labels of every level of the tree, see `mnemonic::validate_level`.
+    pub fn validate(&self) -> Vec<LabelProblem> {
+        let mut problems = Vec::new();
+        validate_tree(&self.items, &mut Vec::new(), &mut problems);
+        problems
+    }
+}
+fn check_level_keys(items: &[ModelItem], path: &mut Vec<u32>) -> Result<(), DuplicateKey> {
+    let mut keys = HashSet::new();
+    for (i, item) in items.iter().enumerate() {
+        if !keys.insert(item.key()) {
+            return Err(DuplicateKey { path: path.clone(), key: item.key().to_owned() });
+        }
+        if let ModelItem::Submenu { ref items, .. } = *item {
+            path.push(i as u32);
+            check_level_keys(items, path)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+fn validate_tree(items: &[ModelItem], path: &mut Vec<u32>, out: &mut Vec<LabelProblem>) {
+    let labels = items.iter().enumerate().filter_map(|(i, item)| match *item {
+        ModelItem::Command { ref label, .. } | ModelItem::Submenu { ref label, .. } => {
+            Some((i as u32, &label[..]))
+        },
+        ModelItem::Separator { .. } => None,
+    });
+    out.extend(validate_level(path, labels));
+    for (i, item) in items.iter().enumerate() {
+        if let ModelItem::Submenu { ref items, .. } = *item {
+            path.push(i as u32);
+            validate_tree(items, path, out);
+            path.pop();
+        }
+    }
+}
+// Items that are kept in place are found with a longest common subsequence, everything else is
+// removed back to front and then inserted front to back, which keeps every position valid at the
+// moment its patch is applied.
+fn diff_level(old: &[ModelItem], new: &[ModelItem], path: &mut Vec<u32>, out: &mut Vec<Patch>) {
+    let kept = common_subsequence(old, new);
+    let mut kept_old = vec![false; old.len()];
+    let mut kept_new = vec![None; new.len()];
+    for &(i, j) in &kept {
+        kept_old[i] = true;
+        kept_new[j] = Some(i);
+    }
+    for i in (0..old.len()).rev().filter(|&i| !kept_old[i]) {
+        out.push(Patch::Remove { path: path.clone(), position: i as u32 });
+    }
+    for (j, item) in new.iter().enumerate() {
+        let position = j as u32;
+        let i = match kept_new[j] {
+            Some(i) => i,
+            None => {
+                let item = item.clone();
+                out.push(Patch::Insert { path: path.clone(), position: position, item: item });
+                continue;
+            },
+        };
+        match (&old[i], item) {
+            (&ModelItem::Command { label: ref l1, status: s1, check: c1, .. },
+                &ModelItem::Command { label: ref l2, status: s2, check: c2, .. }) => {
+                modify(path, position, (l1, s1, Some(c1)), (l2, s2, Some(c2)), out);
+            },
+            (&ModelItem::Submenu { label: ref l1, status: s1, items: ref i1, .. },
+                &ModelItem::Submenu { label: ref l2, status: s2, items: ref i2, .. }) => {
+                modify(path, position, (l1, s1, None), (l2, s2, None), out);
+                path.push(position);
+                diff_level(i1, i2, path, out);
+                path.pop();
+            },
+            _ => (),
+        }
+    }
+}
+fn modify(
+    path: &[u32], position: u32, old: (&String, MenuStatus, Option<MenuCheck>),
+    new: (&String, MenuStatus, Option<MenuCheck>), out: &mut Vec<Patch>,
+) {
+    let label = if old.0 != new.0 { Some(new.0.clone()) } else { None };
+    let status = if old.1 != new.1 { Some(new.1) } else { None };
+    let check = if old.2 != new.2 { new.2 } else { None };
+    if label.is_some() || status.is_some() || check.is_some() {
+        out.push(Patch::Modify {
+            path: path.to_vec(),
+            position: position,
+            label: label,
+            status: status,
+            check: check,
+        });
+    }
+}
+// Pairs of indices into `old` and `new` of the items that can stay where they are.
+fn common_subsequence(old: &[ModelItem], new: &[ModelItem]) -> Vec<(usize, usize)> {
+    let (n, m) = (old.len(), new.len());
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lengths = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i][j] = if old[i].matches(&new[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i].matches(&new[j]) {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+fn outline_level(items: &[ModelItem], depth: usize, out: &mut String) {
+    for item in items {
+        for _ in 0..depth {
+            out.push_str("  ");
+        }
+        match *item {
+            ModelItem::Command { ref label, id, status, check, .. } => {
+                out.push_str(match check {
+                    MenuCheck::Checked => "[x] ",
+                    MenuCheck::Unchecked => "",
+                    MenuCheck::Radio => "(*) ",
+                    MenuCheck::RadioUnchecked => "( ) ",
+                });
+                let _ = write!(out, "{} #{}", label, id);
+                outline_status(status, out);
+            },
+            ModelItem::Separator { .. } => out.push_str("---"),
+            ModelItem::Submenu { ref label, status, ref items, .. } => {
+                let _ = write!(out, "{} >", label);
+                outline_status(status, out);
+                out.push('\n');
+                outline_level(items, depth + 1, out);
+                continue;
+            },
+        }
+        out.push('\n');
+    }
+}
+fn outline_status(status: MenuStatus, out: &mut String) {
+    out.push_str(match status {
+        MenuStatus::Enabled => "",
+        MenuStatus::Disabled => " (disabled)",
+        MenuStatus::Grayed => " (grayed)",
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(key: &str, id: u16, check: MenuCheck) -> ModelItem {
+        ModelItem::Command {
+            key: key.to_owned(),
+            label: key.to_owned(),
+            id: id,
+            status: MenuStatus::Enabled,
+            check: check,
+        }
+    }
+    fn separator(key: &str) -> ModelItem {
+        ModelItem::Separator { key: key.to_owned() }
+    }
+    fn submenu(key: &str, items: Vec<ModelItem>) -> ModelItem {
+        ModelItem::Submenu {
+            key: key.to_owned(),
+            label: key.to_owned(),
+            status: MenuStatus::Enabled,
+            items: items,
+        }
+    }
+    // Applies patches to a model the way `retained` applies them to a live menu.
+    fn apply(model: &mut MenuModel, patches: &[Patch]) {
+        for patch in patches {
+            let path = match *patch {
+                Patch::Remove { ref path, .. } => path,
+                Patch::Insert { ref path, .. } => path,
+                Patch::Modify { ref path, .. } => path,
+            };
+            let mut items = &mut model.items;
+            for &p in path {
+                items = match items[p as usize] {
+                    ModelItem::Submenu { ref mut items, .. } => items,
+                    _ => panic!("path goes through a non-submenu"),
+                };
+            }
+            match *patch {
+                Patch::Remove { position, .. } => {
+                    items.remove(position as usize);
+                },
+                Patch::Insert { position, ref item, .. } => {
+                    items.insert(position as usize, item.clone());
+                },
+                Patch::Modify { position, ref label, status, check, .. } => {
+                    match items[position as usize] {
+                        ModelItem::Command {
+                            label: ref mut l, status: ref mut s, check: ref mut c, ..
+                        } => {
+                            *l = label.clone().unwrap_or(l.clone());
+                            *s = status.unwrap_or(*s);
+                            *c = check.unwrap_or(*c);
+                        },
+                        ModelItem::Submenu { label: ref mut l, status: ref mut s, .. } => {
+                            *l = label.clone().unwrap_or(l.clone());
+                            *s = status.unwrap_or(*s);
+                        },
+                        ModelItem::Separator { .. } => panic!("modified a separator"),
+                    }
+                },
+            }
+        }
+    }
+    fn diff_and_apply(old: &MenuModel, new: &MenuModel) -> Vec<Patch> {
+        let patches = old.diff(new).unwrap();
+        let mut patched = old.clone();
+        apply(&mut patched, &patches);
+        assert_eq!(patched.outline(), new.outline());
+        patches
+    }
+
+    #[test]
+    fn unchanged() {
+        let model = MenuModel {
+            items: vec![command("Pause", 1, MenuCheck::Unchecked), separator("sep")],
+        };
+        assert_eq!(model.diff(&model.clone()), Ok(vec![]));
+    }
+    #[test]
+    fn modify_in_place() {
+        let old = MenuModel {
+            items: vec![
+                command("Pause", 1, MenuCheck::Unchecked),
+                command("Autostart", 2, MenuCheck::Unchecked),
+            ],
+        };
+        let mut new = old.clone();
+        new.items[1] = ModelItem::Command {
+            key: "Autostart".to_owned(),
+            label: "Start with Windows".to_owned(),
+            id: 2,
+            status: MenuStatus::Enabled,
+            check: MenuCheck::Checked,
+        };
+        assert_eq!(diff_and_apply(&old, &new), vec![Patch::Modify {
+            path: vec![],
+            position: 1,
+            label: Some("Start with Windows".to_owned()),
+            status: None,
+            check: Some(MenuCheck::Checked),
+        }]);
+        assert_eq!(new.outline(), "Pause #1\n[x] Start with Windows #2\n");
+    }
+    #[test]
+    fn insert_and_remove() {
+        let old = MenuModel {
+            items: vec![
+                command("A", 1, MenuCheck::Unchecked),
+                command("B", 2, MenuCheck::Unchecked),
+                command("C", 3, MenuCheck::Unchecked),
+                command("D", 4, MenuCheck::Unchecked),
+            ],
+        };
+        let new = MenuModel {
+            items: vec![
+                command("A", 1, MenuCheck::Unchecked),
+                separator("sep"),
+                command("C", 3, MenuCheck::Unchecked),
+                command("E", 5, MenuCheck::Unchecked),
+            ],
+        };
+        assert_eq!(diff_and_apply(&old, &new), vec![
+            Patch::Remove { path: vec![], position: 3 },
+            Patch::Remove { path: vec![], position: 1 },
+            Patch::Insert { path: vec![], position: 1, item: separator("sep") },
+            Patch::Insert {
+                path: vec![],
+                position: 3,
+                item: command("E", 5, MenuCheck::Unchecked),
+            },
+        ]);
+        assert_eq!(new.outline(), "A #1\n---\nC #3\nE #5\n");
+    }
+    #[test]
+    fn changed_id_replaces() {
+        let old = MenuModel { items: vec![command("A", 1, MenuCheck::Unchecked)] };
+        let new = MenuModel { items: vec![command("A", 2, MenuCheck::Unchecked)] };
+        assert_eq!(diff_and_apply(&old, &new), vec![
+            Patch::Remove { path: vec![], position: 0 },
+            Patch::Insert {
+                path: vec![],
+                position: 0,
+                item: command("A", 2, MenuCheck::Unchecked),
+            },
+        ]);
+    }
+    #[test]
+    fn moved_item() {
+        let old = MenuModel {
+            items: vec![
+                command("A", 1, MenuCheck::Unchecked),
+                command("B", 2, MenuCheck::Unchecked),
+                command("C", 3, MenuCheck::Unchecked),
+            ],
+        };
+        let new = MenuModel {
+            items: vec![
+                command("C", 3, MenuCheck::Unchecked),
+                command("A", 1, MenuCheck::Unchecked),
+                command("B", 2, MenuCheck::Unchecked),
+            ],
+        };
+        assert_eq!(diff_and_apply(&old, &new), vec![
+            Patch::Remove { path: vec![], position: 2 },
+            Patch::Insert {
+                path: vec![],
+                position: 0,
+                item: command("C", 3, MenuCheck::Unchecked),
+            },
+        ]);
+    }
+    #[test]
+    fn nested() {
+        let old = MenuModel {
+            items: vec![
+                command("Pause", 1, MenuCheck::Unchecked),
+                submenu("Mode", vec![
+                    command("Fast", 3, MenuCheck::Radio),
+                    command("Quiet", 4, MenuCheck::RadioUnchecked),
+                ]),
+            ],
+        };
+        let new = MenuModel {
+            items: vec![
+                command("Pause", 1, MenuCheck::Unchecked),
+                submenu("Mode", vec![
+                    command("Fast", 3, MenuCheck::RadioUnchecked),
+                    command("Quiet", 4, MenuCheck::Radio),
+                    command("Off", 5, MenuCheck::RadioUnchecked),
+                ]),
+            ],
+        };
+        let modify = |position, check| Patch::Modify {
+            path: vec![1],
+            position: position,
+            label: None,
+            status: None,
+            check: Some(check),
+        };
+        assert_eq!(diff_and_apply(&old, &new), vec![
+            modify(0, MenuCheck::RadioUnchecked),
+            modify(1, MenuCheck::Radio),
+            Patch::Insert {
+                path: vec![1],
+                position: 2,
+                item: command("Off", 5, MenuCheck::RadioUnchecked),
+            },
+        ]);
+        let outline = "Pause #1\nMode >\n  ( ) Fast #3\n  (*) Quiet #4\n  ( ) Off #5\n";
+        assert_eq!(new.outline(), outline);
+    }
+    #[test]
+    fn from_empty() {
+        let new = MenuModel {
+            items: vec![submenu("Mode", vec![command("Fast", 3, MenuCheck::Radio)])],
+        };
+        let patches = diff_and_apply(&MenuModel::new(), &new);
+        assert_eq!(patches.len(), 1);
+        assert_eq!(new.outline(), "Mode >\n  (*) Fast #3\n");
+    }
+    #[test]
+    fn duplicate_keys() {
+        let good = MenuModel { items: vec![command("A", 1, MenuCheck::Unchecked)] };
+        let top = MenuModel {
+            items: vec![command("A", 1, MenuCheck::Unchecked), separator("A")],
+        };
+        let nested = MenuModel {
+            items: vec![
+                command("A", 1, MenuCheck::Unchecked),
+                submenu("Sub", vec![separator("A"), separator("B"), separator("B")]),
+            ],
+        };
+        let error = DuplicateKey { path: vec![], key: "A".to_owned() };
+        assert_eq!(good.diff(&top), Err(error.clone()));
+        assert_eq!(top.diff(&good), Err(error));
+        let error = DuplicateKey { path: vec![1], key: "B".to_owned() };
+        assert_eq!(nested.check_keys(), Err(error.clone()));
+        assert_eq!(error.to_string(), "key \"B\" is used more than once in menu [1]");
+        // The same key may be used on different levels.
+        let model = MenuModel { items: vec![submenu("A", vec![separator("A")])] };
+        assert_eq!(model.check_keys(), Ok(()));
+    }
+}
@@ -0,0 +1,197 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+// Menu labels mark their access key with a '&' in front of it, write a literal '&' as "&&", and
+// put the shortcut shown on the right after a tab. These helpers build and check labels in that
+// syntax.
+
+// Windows accepts far longer labels, but past this the menu gets wider than many screens.
+pub const MAX_LABEL_LENGTH: usize = 80;
+
+// Makes any text safe to show as it is, such as a file or device name.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&&")
+}
+// Escapes the text and marks the first occurrence of the key as its access key, ignoring case.
+// When the text doesn't contain the key it is appended in parentheses, as is done for labels in
+// scripts without latin letters, for example "Datei (&F)". Returns `None` for keys that can't be
+// typed as an access key: '&' itself, whitespace and control characters.
+pub fn with_access_key(text: &str, key: char) -> Option<String> {
+    if key == '&' || key.is_whitespace() || key.is_control() {
+        return None;
+    }
+    let mut out = String::with_capacity(text.len() + 4);
+    let mut marked = false;
+    for c in text.chars() {
+        if !marked && eq_ignore_case(c, key) {
+            out.push('&');
+            marked = true;
+        }
+        if c == '&' {
+            out.push('&');
+        }
+        out.push(c);
+    }
+    if !marked {
+        out.push_str(" (&");
+        out.push(key);
+        out.push(')');
+    }
+    Some(out)
+}
+// The access key marked in the label, lowercased. A trailing '&' marks nothing.
+pub fn access_key(label: &str) -> Option<char> {
+    let mut chars = text_part(label).chars();
+    while let Some(c) = chars.next() {
+        if c == '&' {
+            match chars.next() {
+                Some('&') => (),
+                Some(key) => return key.to_lowercase().next(),
+                None => return None,
+            }
+        }
+    }
+    None
+}
+// The text as it appears in the menu, without the markers or the shortcut.
+pub fn display_text(label: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text_part(label).chars();
+    while let Some(c) = chars.next() {
+        if c == '&' {
+            if let Some(c) = chars.next() {
+                out.push(c);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+fn text_part(label: &str) -> &str {
+    label.split('\t').next().unwrap_or("")
+}
+fn eq_ignore_case(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LabelProblemKind {
+    // Pressing the key cycles between the items instead of choosing one. Holds the position of the
+    // earlier item using the same key.
+    DuplicateAccessKey(char, u32),
+    // Holds the length of the displayed text in characters.
+    TooLong(usize),
+    // A lone '&' at the end of the text, which is most likely an unescaped literal.
+    DanglingAmpersand,
+}
+// Identifies the label by the positions leading to it, starting from the top menu, like the paths
+// in `menumodel::Patch` but including the position of the item itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelProblem {
+    pub path: Vec<u32>,
+    pub kind: LabelProblemKind,
+}
+impl fmt::Display for LabelProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path: Vec<String> = self.path.iter().map(|p| p.to_string()).collect();
+        write!(f, "item {}: ", path.join("."))?;
+        match self.kind {
+            LabelProblemKind::DuplicateAccessKey(key, other) => {
+                write!(f, "access key {:?} is also used by item {}", key, other)
+            },
+            LabelProblemKind::TooLong(length) => {
+                write!(f, "label is {} characters long, over the limit of {}", length,
+                    MAX_LABEL_LENGTH)
+            },
+            LabelProblemKind::DanglingAmpersand => f.write_str("label ends with a lone '&'"),
+        }
+    }
+}
+impl StdError for LabelProblem {}
+// Checks the labels of one level of a menu, where `labels` yields the position and label of each
+// item that has one. Separators are simply left out. Problems are reported under `path`.
+pub fn validate_level<'a, I>(path: &[u32], labels: I) -> Vec<LabelProblem>
+    where I: IntoIterator<Item = (u32, &'a str)>
+{
+    let mut problems = Vec::new();
+    let mut keys: Vec<(char, u32)> = Vec::new();
+    let problem = |position: u32, kind| {
+        let mut path = path.to_vec();
+        path.push(position);
+        LabelProblem { path: path, kind: kind }
+    };
+    for (position, label) in labels {
+        let text = text_part(label);
+        if text.ends_with('&') && text.chars().rev().take_while(|&c| c == '&').count() % 2 == 1 {
+            problems.push(problem(position, LabelProblemKind::DanglingAmpersand));
+        }
+        let length = display_text(label).chars().count();
+        if length > MAX_LABEL_LENGTH {
+            problems.push(problem(position, LabelProblemKind::TooLong(length)));
+        }
+        if let Some(key) = access_key(label) {
+            match keys.iter().find(|&&(k, _)| k == key) {
+                Some(&(_, other)) => {
+                    let kind = LabelProblemKind::DuplicateAccessKey(key, other);
+                    problems.push(problem(position, kind));
+                },
+                None => keys.push((key, position)),
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_keys() {
+        assert_eq!(with_access_key("Open", 'o'), Some("&Open".to_owned()));
+        assert_eq!(with_access_key("Save As", 'A'), Some("S&ave As".to_owned()));
+        assert_eq!(with_access_key("Salt & Pepper", 'p'), Some("Salt && &Pepper".to_owned()));
+        assert_eq!(with_access_key("Datei", 'F'), Some("Datei (&F)".to_owned()));
+        assert_eq!(with_access_key("A&B", 'x'), Some("A&&B (&x)".to_owned()));
+        assert_eq!(with_access_key("Ändern", 'ä'), Some("&Ändern".to_owned()));
+        for &key in &['&', ' ', '\t', '\0', '\u{7f}'] {
+            assert_eq!(with_access_key("A & B", key), None);
+        }
+        for &(text, key) in &[("Open", 'o'), ("Salt & Pepper", 'p'), ("Datei", 'F')] {
+            let label = with_access_key(text, key).unwrap();
+            assert_eq!(access_key(&label), key.to_lowercase().next());
+        }
+        assert_eq!(display_text("Salt && &Pepper\tCtrl+P"), "Salt & Pepper");
+        assert_eq!(access_key("A&&B"), None);
+        assert_eq!(access_key("A&"), None);
+    }
+    #[test]
+    fn validate() {
+        let long = "x".repeat(MAX_LABEL_LENGTH + 1);
+        let labels = vec![
+            (0, "&Open"),
+            (2, "&options\tCtrl+O"),
+            (3, "Salt &&"),
+            (4, "Salt &"),
+            (5, &long[..]),
+            (6, "&Close"),
+        ];
+        assert_eq!(validate_level(&[1], labels), vec![
+            LabelProblem {
+                path: vec![1, 2],
+                kind: LabelProblemKind::DuplicateAccessKey('o', 0),
+            },
+            LabelProblem { path: vec![1, 4], kind: LabelProblemKind::DanglingAmpersand },
+            LabelProblem {
+                path: vec![1, 5],
+                kind: LabelProblemKind::TooLong(MAX_LABEL_LENGTH + 1),
+            },
+        ]);
+        assert_eq!(validate_level(&[], vec![(0, "&Open"), (1, "&Close")]), vec![]);
+        let problem = LabelProblem {
+            path: vec![1, 2],
+            kind: LabelProblemKind::DuplicateAccessKey('o', 0),
+        };
+        assert_eq!(problem.to_string(), "item 1.2: access key 'o' is also used by item 0");
+    }
+}
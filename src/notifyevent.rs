@@ -0,0 +1,162 @@
+use msgname::{WM_APP, WM_USER};
+
+// The notifications a notification icon sends to its window, decoded from the raw callback message
+// without asking the system for anything, so that the decoding can be tested anywhere.
+
+// Icons using version 4 and version 3 lay out their notifications differently, so they are given
+// different callback messages to tell them apart.
+pub const WM_APP_NOTIFICATION_ICON: u32 = WM_APP + 1;
+pub const WM_APP_NOTIFICATION_ICON_V3: u32 = WM_APP + 2;
+
+const NIN_SELECT: u32 = WM_USER;
+const NIN_KEYSELECT: u32 = WM_USER + 1;
+const NIN_BALLOONSHOW: u32 = WM_USER + 2;
+const NIN_BALLOONHIDE: u32 = WM_USER + 3;
+const NIN_BALLOONTIMEOUT: u32 = WM_USER + 4;
+const NIN_BALLOONUSERCLICK: u32 = WM_USER + 5;
+const NIN_POPUPOPEN: u32 = WM_USER + 6;
+const NIN_POPUPCLOSE: u32 = WM_USER + 7;
+const WM_CONTEXTMENU: u32 = 0x007B;
+const WM_MOUSEMOVE: u32 = 0x0200;
+const WM_LBUTTONDOWN: u32 = 0x0201;
+const WM_LBUTTONUP: u32 = 0x0202;
+const WM_LBUTTONDBLCLK: u32 = 0x0203;
+const WM_RBUTTONDOWN: u32 = 0x0204;
+const WM_RBUTTONUP: u32 = 0x0205;
+const WM_RBUTTONDBLCLK: u32 = 0x0206;
+const WM_MBUTTONDOWN: u32 = 0x0207;
+const WM_MBUTTONUP: u32 = 0x0208;
+const WM_MBUTTONDBLCLK: u32 = 0x0209;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+// The key that activated the icon, as far as can be told from the state of the keyboard when the
+// notification arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectKey {
+    Enter,
+    Space,
+    Unknown,
+}
+// Coordinates are in screen space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotifyIconEvent {
+    ContextMenu(i32, i32),
+    MouseMove(i32, i32),
+    ButtonDown(MouseButton, i32, i32),
+    ButtonUp(MouseButton, i32, i32),
+    DoubleClick(MouseButton, i32, i32),
+    // Chosen with a single click, or with the keyboard before version 4.
+    Select(i32, i32),
+    // Chosen with the keyboard. Pressing Enter sends this twice.
+    KeySelect(i32, i32, SelectKey),
+    // The pointer is resting on the icon, so a rich tooltip may be shown in place of the regular
+    // one. Only sent for version 4 icons without a tooltip set.
+    PopupOpen(i32, i32),
+    PopupClose,
+    BalloonShow,
+    // The balloon was closed, either by the user or because the icon was removed.
+    BalloonHide,
+    BalloonTimeout,
+    BalloonUserClick,
+    Unknown(u32, i32, i32),
+}
+// Splits the callback message of an icon into its id and event. Version 3 messages carry no point,
+// so `cursor` is asked for one, and `key` is only asked for the keyboard state on key selections.
+pub fn decode_notify_icon<C, K>(
+    msg: u32, wparam: usize, lparam: isize, cursor: C, key: K,
+) -> (u16, NotifyIconEvent)
+    where C: FnOnce() -> (i32, i32), K: FnOnce() -> SelectKey
+{
+    if msg == WM_APP_NOTIFICATION_ICON {
+        // The id is in the high word and the anchor point in wparam, as signed words like
+        // GET_X_LPARAM and GET_Y_LPARAM read them.
+        let (x, y) = (wparam as u16 as i16 as i32, (wparam >> 16) as u16 as i16 as i32);
+        let event = NotifyIconEvent::from_raw(lparam as u16 as u32, x, y, key);
+        ((lparam >> 16) as u16, event)
+    } else {
+        // The id is in wparam and there is no point, so the cursor position is used instead.
+        let (x, y) = cursor();
+        (wparam as u16, NotifyIconEvent::from_raw(lparam as u32, x, y, key))
+    }
+}
+impl NotifyIconEvent {
+    fn from_raw<K>(msg: u32, x: i32, y: i32, key: K) -> NotifyIconEvent
+        where K: FnOnce() -> SelectKey
+    {
+        match msg {
+            NIN_SELECT => NotifyIconEvent::Select(x, y),
+            NIN_KEYSELECT => NotifyIconEvent::KeySelect(x, y, key()),
+            NIN_POPUPOPEN => NotifyIconEvent::PopupOpen(x, y),
+            NIN_POPUPCLOSE => NotifyIconEvent::PopupClose,
+            WM_LBUTTONDOWN => NotifyIconEvent::ButtonDown(MouseButton::Left, x, y),
+            WM_RBUTTONDOWN => NotifyIconEvent::ButtonDown(MouseButton::Right, x, y),
+            WM_MBUTTONDOWN => NotifyIconEvent::ButtonDown(MouseButton::Middle, x, y),
+            WM_LBUTTONUP => NotifyIconEvent::ButtonUp(MouseButton::Left, x, y),
+            WM_RBUTTONUP => NotifyIconEvent::ButtonUp(MouseButton::Right, x, y),
+            WM_MBUTTONUP => NotifyIconEvent::ButtonUp(MouseButton::Middle, x, y),
+            WM_LBUTTONDBLCLK => NotifyIconEvent::DoubleClick(MouseButton::Left, x, y),
+            WM_RBUTTONDBLCLK => NotifyIconEvent::DoubleClick(MouseButton::Right, x, y),
+            WM_MBUTTONDBLCLK => NotifyIconEvent::DoubleClick(MouseButton::Middle, x, y),
+            NIN_BALLOONSHOW => NotifyIconEvent::BalloonShow,
+            NIN_BALLOONHIDE => NotifyIconEvent::BalloonHide,
+            NIN_BALLOONTIMEOUT => NotifyIconEvent::BalloonTimeout,
+            NIN_BALLOONUSERCLICK => NotifyIconEvent::BalloonUserClick,
+            WM_CONTEXTMENU => NotifyIconEvent::ContextMenu(x, y),
+            WM_MOUSEMOVE => NotifyIconEvent::MouseMove(x, y),
+            _ => NotifyIconEvent::Unknown(msg, x, y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds the message a version 4 or version 3 icon with id 7 sends, with the point at (x, y)
+    // for version 4, and decodes it with the cursor at (100, 200) and Space held down.
+    fn decode(msg: u32, version: u32, x: i32, y: i32) -> (u16, NotifyIconEvent) {
+        let (callback, wparam, lparam) = if version == 4 {
+            let point = (x as u16 as u32 | (y as u16 as u32) << 16) as usize;
+            (WM_APP_NOTIFICATION_ICON, point, (7 << 16 | msg) as isize)
+        } else {
+            (WM_APP_NOTIFICATION_ICON_V3, 7, msg as isize)
+        };
+        decode_notify_icon(callback, wparam, lparam, || (100, 200), || SelectKey::Space)
+    }
+
+    #[test]
+    fn notify_icon_events() {
+        use self::NotifyIconEvent::*;
+        let table = [
+            (NIN_SELECT, 4, 10, 20, Select(10, 20)),
+            (NIN_SELECT, 3, 10, 20, Select(100, 200)),
+            (NIN_KEYSELECT, 4, 10, 20, KeySelect(10, 20, SelectKey::Space)),
+            (NIN_KEYSELECT, 3, 10, 20, KeySelect(100, 200, SelectKey::Space)),
+            // Monitors left of or above the primary one have negative coordinates.
+            (WM_CONTEXTMENU, 4, -1920, -5, ContextMenu(-1920, -5)),
+            (WM_CONTEXTMENU, 3, -1920, -5, ContextMenu(100, 200)),
+            (WM_LBUTTONUP, 4, 1, 2, ButtonUp(MouseButton::Left, 1, 2)),
+            (WM_RBUTTONDBLCLK, 3, 1, 2, DoubleClick(MouseButton::Right, 100, 200)),
+            (WM_MOUSEMOVE, 4, 32767, -32768, MouseMove(32767, -32768)),
+            (NIN_POPUPCLOSE, 4, 1, 2, PopupClose),
+            (NIN_BALLOONUSERCLICK, 3, 1, 2, BalloonUserClick),
+            (0x1234, 4, 1, 2, Unknown(0x1234, 1, 2)),
+        ];
+        for &(msg, version, x, y, expected) in &table {
+            assert_eq!(decode(msg, version, x, y), (7, expected), "{:#x} {}", msg, version);
+        }
+    }
+    #[test]
+    fn lazy_state() {
+        let event = decode_notify_icon(
+            WM_APP_NOTIFICATION_ICON, 0, (1 << 16 | WM_MOUSEMOVE) as isize,
+            || panic!("version 4 has a point"), || panic!("not a key selection"),
+        );
+        assert_eq!(event, (1, NotifyIconEvent::MouseMove(0, 0)));
+    }
+}
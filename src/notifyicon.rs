@@ -1,39 +1,367 @@
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::error::Error as StdError;
+use std::fmt;
 use std::mem::{size_of, zeroed};
-use std::rc::Rc;
+use std::ptr::null_mut;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use winapi::shared::guiddef::GUID;
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+use winapi::shared::windef::{HICON, RECT};
 use winapi::um::shellapi::{
-    NIF_ICON, NIF_MESSAGE, NIF_SHOWTIP, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY, NIM_SETVERSION,
-    NOTIFYICONDATAW, NOTIFYICON_VERSION_4, Shell_NotifyIconW,
+    NIF_GUID, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_REALTIME, NIF_SHOWTIP, NIF_STATE, NIF_TIP,
+    NIIF_ERROR, NIIF_INFO, NIIF_LARGE_ICON, NIIF_NONE, NIIF_NOSOUND, NIIF_RESPECT_QUIET_TIME,
+    NIIF_USER, NIIF_WARNING, NIM_ADD, NIM_DELETE, NIM_MODIFY, NIM_SETVERSION, NIS_HIDDEN,
+    NIS_SHAREDICON, NOTIFYICONDATAW, NOTIFYICONIDENTIFIER, NOTIFYICON_VERSION, NOTIFYICON_VERSION_4,
+    Shell_NotifyIconGetRect, Shell_NotifyIconW,
 };
+use winapi::um::winuser::{GetSystemMetrics, KillTimer, SM_MENUDROPALIGNMENT, SetTimer};
 
 use Error;
-use event::{EventResponse, NotifyIconEvent, WM_APP_NOTIFICATION_ICON};
+use event::{
+    EventResponse, NotifyIconEvent, WM_APP_NOTIFICATION_ICON, WM_APP_NOTIFICATION_ICON_V3,
+};
+use flyout::Taskbar;
+use geometry::Rect;
 use icon::Icon;
+use menu::{HorizontalAlign, MenuItem, PopupMenu, PopupOptions, VerticalAlign};
+use placement::Edge;
+use theme::{ThemeMode, ThemePreference};
 use wide::ToWide;
 use window::Window;
 
 struct NotifyIconInternal {
     nid: RefCell<NOTIFYICONDATAW>,
     handler: Box<Fn(NotifyIconEvent, &Window) -> Option<EventResponse>>,
+    version: NotifyIconVersion,
+    // The icon shown whenever no animation is running, unless there is a themed pair.
+    icon: RefCell<Option<Icon>>,
+    themed: RefCell<Option<ThemedIcon>>,
+    animation: RefCell<Option<Animation>>,
+    // Kept alive for as long as the balloon showing it might be.
+    balloon_icon: RefCell<Option<Icon>>,
+    removed: Cell<bool>,
+    context_menu: RefCell<Option<ContextMenu>>,
+    default_item: Option<MenuItem>,
+}
+type ContextMenuBuilder = Box<dyn FnMut(&Window) -> Result<PopupMenu, Error>>;
+enum ContextMenu {
+    Fixed(PopupMenu),
+    Lazy(ContextMenuBuilder),
+}
+impl NotifyIconInternal {
+    // The menu is borrowed while it is open, which counts as having one.
+    fn has_context_menu(&self) -> bool {
+        self.context_menu.try_borrow().map(|menu| menu.is_some()).unwrap_or(true)
+    }
+    fn delete(&self) -> Result<(), Error> {
+        if self.removed.replace(true) {
+            return Ok(());
+        }
+        let mut nid = self.nid.borrow_mut();
+        if self.animation.borrow().is_some() {
+            unsafe { KillTimer(nid.hWnd, animation_timer(nid.uID as u16)) };
+        }
+        if unsafe { Shell_NotifyIconW(NIM_DELETE, &mut *nid) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
 }
 impl Drop for NotifyIconInternal {
     fn drop(&mut self) {
-        let err = unsafe { Shell_NotifyIconW(NIM_DELETE, &mut *self.nid.borrow_mut()) };
-        if err == 0 {
-            Error::get_last_error().die("Failed to delete notification icon");
+        // The icon may already be gone, such as when Explorer restarted or the window was
+        // destroyed first, which is no reason to abort.
+        let _ = self.delete();
+    }
+}
+// The taskbar follows the system theme rather than the app theme, so the light variant is shown
+// on a light taskbar and the dark variant on a dark one.
+struct ThemedIcon {
+    light: Icon,
+    dark: Icon,
+}
+impl ThemedIcon {
+    fn select(&self) -> Result<HICON, Error> {
+        Ok(match ThemePreference::query()?.system {
+            ThemeMode::Light => self.light.as_raw(),
+            ThemeMode::Dark => self.dark.as_raw(),
+        })
+    }
+}
+// Animation timers are set on the window, and share their ids with any timers the app sets, so
+// they use ids the app is unlikely to pick, with the icon id in the low word.
+const ANIMATION_TIMER: usize = 0x4E49_0000;
+fn animation_timer(id: u16) -> usize {
+    ANIMATION_TIMER | id as usize
+}
+// The id of the icon the timer is animating, if it is an animation timer at all.
+pub(crate) fn animated_icon(timer: usize) -> Option<u16> {
+    if timer & !0xFFFF == ANIMATION_TIMER { Some(timer as u16) } else { None }
+}
+struct Animation {
+    frames: Vec<Icon>,
+    next: usize,
+}
+// Decides which notifications are sent and how they are laid out. Version 4 is the one to use
+// unless something depends on the older behavior.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotifyIconVersion {
+    Version3,
+    Version4,
+}
+impl NotifyIconVersion {
+    fn as_raw(&self) -> u32 {
+        match *self {
+            NotifyIconVersion::Version3 => NOTIFYICON_VERSION,
+            NotifyIconVersion::Version4 => NOTIFYICON_VERSION_4,
+        }
+    }
+}
+#[derive(Debug)]
+pub enum NotifyIconError {
+    MissingId,
+    // Id 0 is reserved.
+    ZeroId,
+    // The window already has an icon with this id.
+    DuplicateId(u16),
+    // The text doesn't fit in its field of `NOTIFYICONDATAW`, with lengths in UTF-16 units.
+    TooLong {
+        field: &'static str,
+        length: usize,
+        max: usize,
+    },
+    Windows(Error),
+}
+impl From<Error> for NotifyIconError {
+    fn from(err: Error) -> NotifyIconError {
+        NotifyIconError::Windows(err)
+    }
+}
+impl fmt::Display for NotifyIconError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotifyIconError::MissingId => f.write_str("notification icon has no id"),
+            NotifyIconError::ZeroId => f.write_str("notification icon id must not be zero"),
+            NotifyIconError::DuplicateId(id) => {
+                write!(f, "window already has a notification icon with id {}", id)
+            },
+            NotifyIconError::TooLong { field, length, max } => {
+                write!(f, "{} is {} characters long, over the limit of {}", field, length, max)
+            },
+            NotifyIconError::Windows(err) => {
+                write!(f, "notification icon call failed: error {}", err.as_raw())
+            },
+        }
+    }
+}
+impl StdError for NotifyIconError {}
+// Copies the text into a fixed size field, leaving room for the null terminator.
+fn copy_text(field: &'static str, text: &str, dest: &mut [u16]) -> Result<(), NotifyIconError> {
+    let text = text.to_wide();
+    if text.len() >= dest.len() {
+        return Err(NotifyIconError::TooLong {
+            field: field,
+            length: text.len(),
+            max: dest.len() - 1,
+        });
+    }
+    for x in dest.iter_mut() {
+        *x = 0;
+    }
+    dest[..text.len()].copy_from_slice(&text);
+    Ok(())
+}
+pub enum BalloonIcon {
+    None,
+    Info,
+    Warning,
+    Error,
+    Custom(Icon),
+}
+pub struct Balloon {
+    pub title: String,
+    pub text: String,
+    pub icon: BalloonIcon,
+    // Uses the large version of a custom icon.
+    pub large_icon: bool,
+    pub sound: bool,
+    // Holds the balloon back during quiet time, such as the first hour after a user first logs
+    // in, instead of showing it regardless.
+    pub respect_quiet_time: bool,
+    // Discards the balloon if it can't be shown right away rather than queueing it.
+    pub realtime: bool,
+}
+impl Balloon {
+    pub fn new(title: &str, text: &str) -> Balloon {
+        Balloon {
+            title: title.to_owned(),
+            text: text.to_owned(),
+            icon: BalloonIcon::None,
+            large_icon: false,
+            sound: true,
+            respect_quiet_time: true,
+            realtime: false,
         }
     }
 }
+// Opens the menu away from the taskbar, so that it covers neither the taskbar nor the icon. Along
+// a horizontal taskbar it opens the way menus drop in the current language.
+fn menu_alignment() -> (HorizontalAlign, VerticalAlign) {
+    let right_to_left = unsafe { GetSystemMetrics(SM_MENUDROPALIGNMENT) } != 0;
+    let horizontal = if right_to_left { HorizontalAlign::Right } else { HorizontalAlign::Left };
+    match Taskbar::query().map(|taskbar| taskbar.edge).unwrap_or(Edge::Bottom) {
+        Edge::Bottom => (horizontal, VerticalAlign::Bottom),
+        Edge::Top => (horizontal, VerticalAlign::Top),
+        Edge::Left => (HorizontalAlign::Left, VerticalAlign::Bottom),
+        Edge::Right => (HorizontalAlign::Right, VerticalAlign::Bottom),
+    }
+}
+// The window only keeps weak references to its icons, so that an icon goes away as soon as the
+// last handle to it is dropped.
+#[derive(Clone)]
+pub(crate) struct WeakNotifyIcon(Weak<NotifyIconInternal>);
+impl WeakNotifyIcon {
+    // None once the icon was dropped or removed.
+    pub(crate) fn upgrade(&self) -> Option<NotifyIcon> {
+        self.0.upgrade().filter(|ni| !ni.removed.get()).map(NotifyIcon)
+    }
+}
+// Removed from the taskbar when the last handle is dropped.
 #[derive(Clone)]
 pub struct NotifyIcon(Rc<NotifyIconInternal>);
 impl NotifyIcon {
+    // The context menu and default item take care of their events, everything else goes to the
+    // handler.
     pub fn handle_event(&self, e: NotifyIconEvent, w: &Window) -> Option<EventResponse> {
-        (self.0.handler)(e, w)
+        match e {
+            NotifyIconEvent::ContextMenu(x, y) if self.0.has_context_menu() => {
+                // There is no one to report a failure to, and the user can simply try again.
+                let _ = self.show_context_menu(w, x, y);
+                None
+            },
+            // Not done for `KeySelect`, which arrives twice when pressing Enter.
+            NotifyIconEvent::Select(..) => match self.0.default_item {
+                Some(MenuItem::Id(id)) => w.send_command(id),
+                // Looked up in the menu, and sent while that is still alive in case it was only
+                // built for this.
+                Some(item) => match self.with_context_menu(w, |menu| {
+                    menu.item_info(item).ok().and_then(|info| info.id).map(|id| w.send_command(id))
+                }) {
+                    Ok(Some(Some(response))) => response,
+                    _ => (self.0.handler)(e, w),
+                },
+                None => (self.0.handler)(e, w),
+            },
+            _ => (self.0.handler)(e, w),
+        }
+    }
+    // Shows the context menu at the point, which for version 4 icons is where the notification
+    // says, whether the menu was opened with the mouse or the keyboard. Items without a callback
+    // are sent to the window as `Event::MenuCommand`.
+    pub fn show_context_menu(&self, window: &Window, x: i32, y: i32) -> Result<(), Error> {
+        let default_item = self.0.default_item;
+        self.with_context_menu(window, |menu| {
+            if default_item.is_some() {
+                menu.set_default_item(default_item)?;
+            }
+            let (horizontal, vertical) = menu_alignment();
+            let options = PopupOptions {
+                horizontal: horizontal,
+                vertical: vertical,
+                right_button: true,
+                ..PopupOptions::default()
+            };
+            menu.display_with(window, x, y, options, |id, window| {
+                window.send_command(id);
+            })
+        })?.unwrap_or(Ok(()))
+    }
+    // Runs `func` with the context menu, building it first for `context_menu_with`. Gives None
+    // without a menu, or while it is open, as messages keep being dispatched then.
+    fn with_context_menu<T, R>(&self, window: &Window, func: T) -> Result<Option<R>, Error>
+        where T: FnOnce(&PopupMenu) -> R
+    {
+        let mut context_menu = match self.0.context_menu.try_borrow_mut() {
+            Ok(context_menu) => context_menu,
+            Err(_) => return Ok(None),
+        };
+        let lazy;
+        let menu = match *context_menu {
+            Some(ContextMenu::Fixed(ref menu)) => menu,
+            Some(ContextMenu::Lazy(ref mut build)) => {
+                lazy = build(window)?;
+                &lazy
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(func(menu)))
     }
     pub fn id(&self) -> u16 {
         self.0.nid.borrow().uID as u16
     }
+    pub fn version(&self) -> NotifyIconVersion {
+        self.0.version
+    }
+    // Where the icon is on the screen. Fails while the icon is hidden in the overflow area.
+    pub fn rect(&self) -> Result<Rect, Error> {
+        let mut id: NOTIFYICONIDENTIFIER = unsafe { zeroed() };
+        id.cbSize = size_of::<NOTIFYICONIDENTIFIER>() as u32;
+        {
+            let nid = self.0.nid.borrow();
+            id.hWnd = nid.hWnd;
+            id.uID = nid.uID;
+            if nid.uFlags & NIF_GUID != 0 {
+                id.guidItem = nid.guidItem;
+            }
+        }
+        let mut r: RECT = unsafe { zeroed() };
+        let hr = unsafe { Shell_NotifyIconGetRect(&id, &mut r) };
+        if hr < 0 {
+            return Err(Error::from_raw(hr as u32));
+        }
+        Ok(Rect::new(r.left, r.top, r.right, r.bottom))
+    }
+    pub(crate) fn downgrade(&self) -> WeakNotifyIcon {
+        WeakNotifyIcon(Rc::downgrade(&self.0))
+    }
+    // Adds the icon back as it was, after Explorer restarted.
+    pub(crate) fn restore(&self) -> Result<(), Error> {
+        if self.is_removed() {
+            return Ok(());
+        }
+        let err = unsafe { Shell_NotifyIconW(NIM_ADD, &mut *self.0.nid.borrow_mut()) };
+        if err == 0 {
+            return Err(Error::get_last_error());
+        }
+        self.set_version()
+    }
+    // Removes the icon from the taskbar right away, even while other handles to it remain. The id
+    // can be used for a new icon afterwards.
+    pub fn remove(&self) -> Result<(), Error> {
+        self.0.delete()
+    }
+    pub fn is_removed(&self) -> bool {
+        self.0.removed.get()
+    }
+    // Hides or shows the icon without removing it, keeping its place among the other icons.
+    pub fn set_hidden(&self, hidden: bool) -> Result<(), Error> {
+        {
+            let mut nid = self.0.nid.borrow_mut();
+            nid.dwStateMask |= NIS_HIDDEN;
+            if hidden {
+                nid.dwState |= NIS_HIDDEN;
+            } else {
+                nid.dwState &= !NIS_HIDDEN;
+            }
+            nid.uFlags |= NIF_STATE;
+        }
+        self.modify()
+    }
+    pub fn is_hidden(&self) -> bool {
+        self.0.nid.borrow().dwState & NIS_HIDDEN != 0
+    }
     fn set_version(&self) -> Result<(), Error> {
         let err = unsafe { Shell_NotifyIconW(NIM_SETVERSION, &mut *self.0.nid.borrow_mut()) };
         if err == 0 {
@@ -52,69 +380,306 @@ impl NotifyIcon {
         }
         Ok(())
     }
-    pub fn set_tooltip(&self, msg: &str) -> Result<(), Error> {
-        let msg = msg.to_wide();
+    pub fn update_theme(&self) -> Result<(), Error> {
+        if self.0.themed.borrow().is_none() || self.is_animating() {
+            return Ok(());
+        }
+        self.show_icon(self.base_icon()?)
+    }
+    // Replaces the icon, including any themed pair. A running animation keeps going and reverts
+    // to the new icon once stopped.
+    pub fn set_icon(&self, icon: Icon) -> Result<(), Error> {
+        let raw = icon.as_raw();
+        if !self.is_animating() {
+            self.show_icon(raw)?;
+        }
+        *self.0.themed.borrow_mut() = None;
+        *self.0.icon.borrow_mut() = Some(icon);
+        Ok(())
+    }
+    // Cycles through the frames until stopped, starting right away. The frames are owned by the
+    // animation and released when it stops. Fails with ERROR_INVALID_PARAMETER without frames.
+    // Intervals too long for a timer are cut to the longest one.
+    pub fn start_animation(&self, frames: Vec<Icon>, interval: Duration) -> Result<(), Error> {
+        if frames.is_empty() {
+            return Err(Error::from_raw(ERROR_INVALID_PARAMETER));
+        }
+        let ms = interval.as_secs().saturating_mul(1000)
+            .saturating_add(interval.subsec_millis() as u64)
+            .min(u32::MAX as u64) as u32;
+        let (hwnd, id) = {
+            let nid = self.0.nid.borrow();
+            (nid.hWnd, nid.uID as u16)
+        };
+        // Setting a timer with the same id again replaces it, so restarting needs no special case.
+        if unsafe { SetTimer(hwnd, animation_timer(id), ms, None) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        *self.0.animation.borrow_mut() = Some(Animation {
+            frames: frames,
+            next: 0,
+        });
+        self.next_frame()
+    }
+    // Stops the animation and goes back to the regular icon.
+    pub fn stop_animation(&self) -> Result<(), Error> {
+        if !self.is_animating() {
+            return Ok(());
+        }
+        // Looked up first, so that failing to get it leaves the animation running.
+        let icon = self.base_icon()?;
+        let animation = self.0.animation.borrow_mut().take();
+        {
+            let nid = self.0.nid.borrow();
+            unsafe { KillTimer(nid.hWnd, animation_timer(nid.uID as u16)) };
+        }
+        let result = self.show_icon(icon);
+        // The frame is only released after the regular icon has replaced it.
+        drop(animation);
+        result
+    }
+    pub fn is_animating(&self) -> bool {
+        self.0.animation.borrow().is_some()
+    }
+    pub(crate) fn next_frame(&self) -> Result<(), Error> {
+        let frame = {
+            let mut animation = self.0.animation.borrow_mut();
+            let animation = match *animation {
+                Some(ref mut animation) => animation,
+                None => return Ok(()),
+            };
+            let frame = animation.frames[animation.next].as_raw();
+            animation.next = (animation.next + 1) % animation.frames.len();
+            frame
+        };
+        self.show_icon(frame)
+    }
+    fn base_icon(&self) -> Result<HICON, Error> {
+        if let Some(ref themed) = *self.0.themed.borrow() {
+            return themed.select();
+        }
+        Ok(self.0.icon.borrow().as_ref().map(|icon| icon.as_raw()).unwrap_or(null_mut()))
+    }
+    fn show_icon(&self, icon: HICON) -> Result<(), Error> {
         {
             let mut nid = self.0.nid.borrow_mut();
-            nid.szTip = [0; 128];
-            nid.szTip[..msg.len()].copy_from_slice(&msg);
-            nid.uFlags |= NIF_TIP | NIF_SHOWTIP;
+            if nid.hIcon == icon {
+                return Ok(());
+            }
+            nid.hIcon = icon;
+            nid.uFlags |= NIF_ICON;
         }
         self.modify()
     }
+    pub fn set_tooltip(&self, msg: &str) -> Result<(), NotifyIconError> {
+        {
+            let mut nid = self.0.nid.borrow_mut();
+            copy_text("tooltip", msg, &mut nid.szTip)?;
+            nid.uFlags |= NIF_TIP | NIF_SHOWTIP;
+        }
+        Ok(self.modify()?)
+    }
+    // Clicking the balloon is reported as `NotifyIconEvent::BalloonUserClick`.
+    pub fn show_balloon(&self, balloon: Balloon) -> Result<(), NotifyIconError> {
+        let mut custom = None;
+        {
+            let mut nid = self.0.nid.borrow_mut();
+            // Both texts are checked before anything is changed, so that a failure leaves the
+            // icon as it was.
+            let (mut title, mut text) = (nid.szInfoTitle, nid.szInfo);
+            copy_text("balloon title", &balloon.title, &mut title)?;
+            copy_text("balloon text", &balloon.text, &mut text)?;
+            nid.szInfoTitle = title;
+            nid.szInfo = text;
+            nid.dwInfoFlags = match balloon.icon {
+                BalloonIcon::None => NIIF_NONE,
+                BalloonIcon::Info => NIIF_INFO,
+                BalloonIcon::Warning => NIIF_WARNING,
+                BalloonIcon::Error => NIIF_ERROR,
+                BalloonIcon::Custom(icon) => {
+                    nid.hBalloonIcon = icon.as_raw();
+                    custom = Some(icon);
+                    NIIF_USER
+                },
+            };
+            if balloon.large_icon { nid.dwInfoFlags |= NIIF_LARGE_ICON }
+            if !balloon.sound { nid.dwInfoFlags |= NIIF_NOSOUND }
+            if balloon.respect_quiet_time { nid.dwInfoFlags |= NIIF_RESPECT_QUIET_TIME }
+            nid.uFlags |= NIF_INFO;
+            if balloon.realtime { nid.uFlags |= NIF_REALTIME }
+        }
+        if custom.is_some() {
+            *self.0.balloon_icon.borrow_mut() = custom;
+        }
+        self.modify_info()
+    }
+    // Dismisses the balloon if it is still showing.
+    pub fn hide_balloon(&self) -> Result<(), NotifyIconError> {
+        {
+            let mut nid = self.0.nid.borrow_mut();
+            nid.szInfo[0] = 0;
+            nid.szInfoTitle[0] = 0;
+            nid.uFlags |= NIF_INFO;
+        }
+        self.modify_info()
+    }
+    // The balloon flags must not stay set, or every later modification would show it again.
+    fn modify_info(&self) -> Result<(), NotifyIconError> {
+        let result = self.modify();
+        self.0.nid.borrow_mut().uFlags &= !(NIF_INFO | NIF_REALTIME);
+        Ok(result?)
+    }
 }
 pub struct NotifyIconBuilder {
     icon: Option<Icon>,
+    themed: Option<ThemedIcon>,
     id: Option<u16>,
+    guid: Option<GUID>,
+    version: NotifyIconVersion,
+    hidden: bool,
+    shared_icon: bool,
     handler: Option<Box<Fn(NotifyIconEvent, &Window) -> Option<EventResponse>>>,
+    context_menu: Option<ContextMenu>,
+    default_item: Option<MenuItem>,
 }
 impl NotifyIconBuilder {
     pub fn new() -> NotifyIconBuilder {
         NotifyIconBuilder {
             icon: None,
+            themed: None,
             id: None,
+            guid: None,
+            version: NotifyIconVersion::Version4,
+            hidden: false,
+            shared_icon: false,
             handler: None,
+            context_menu: None,
+            default_item: None,
         }
     }
     pub fn icon(mut self, icon: Icon) -> NotifyIconBuilder {
         self.icon = Some(icon);
         self
     }
+    // Swaps between the two icons automatically whenever the system theme changes.
+    pub fn themed_icon(mut self, light: Icon, dark: Icon) -> NotifyIconBuilder {
+        self.themed = Some(ThemedIcon {
+            light: light,
+            dark: dark,
+        });
+        self
+    }
+    // Identifies the icon among those of the window, which is how its events are routed. Required
+    // even when a GUID is given.
     pub fn id(mut self, id: u16) -> NotifyIconBuilder {
-        assert!(id != 0, "Notification Icon ID must be non-zero");
         self.id = Some(id);
         self
     }
+    // Identifies the icon to the shell instead of the window and id, which lets the user's choice
+    // to always show it survive the window being recreated. The shell ties the GUID to the path
+    // of the executable, so adding the icon fails if the same GUID is used from elsewhere.
+    pub fn guid(mut self, guid: GUID) -> NotifyIconBuilder {
+        self.guid = Some(guid);
+        self
+    }
+    pub fn version(mut self, version: NotifyIconVersion) -> NotifyIconBuilder {
+        self.version = version;
+        self
+    }
+    // Adds the icon without showing it.
+    pub fn hidden(mut self, hidden: bool) -> NotifyIconBuilder {
+        self.hidden = hidden;
+        self
+    }
+    // Marks the icon as shared with other notification icons, so that it isn't destroyed when any
+    // single one of them is removed.
+    pub fn shared_icon(mut self, shared: bool) -> NotifyIconBuilder {
+        self.shared_icon = shared;
+        self
+    }
+    // Shown on right click or from the keyboard, instead of passing those events to the handler.
+    pub fn context_menu(mut self, menu: PopupMenu) -> NotifyIconBuilder {
+        self.context_menu = Some(ContextMenu::Fixed(menu));
+        self
+    }
+    // Builds the context menu every time it is about to be shown.
+    pub fn context_menu_with<T>(mut self, func: T) -> NotifyIconBuilder
+        where T: FnMut(&Window) -> Result<PopupMenu, Error> + 'static
+    {
+        self.context_menu = Some(ContextMenu::Lazy(Box::new(func)));
+        self
+    }
+    // Shown in bold in the context menu, and chosen when the icon is clicked. Menus built with
+    // `context_menu_with` get new command ids for their callbacks each time, so the default item
+    // has to be a fixed id or a position with them. Positions are looked up in the menu on every
+    // click, building it for `context_menu_with`.
+    pub fn default_item(mut self, item: MenuItem) -> NotifyIconBuilder {
+        self.default_item = Some(item);
+        self
+    }
     pub fn handler<T>(
         mut self, handler: T
     ) -> NotifyIconBuilder where T: Fn(NotifyIconEvent, &Window) -> Option<EventResponse> + 'static {
         self.handler = Some(Box::new(handler));
         self
     }
-    pub fn create(self, window: &Window) -> Result<NotifyIcon, Error> {
+    pub fn create(self, window: &Window) -> Result<NotifyIcon, NotifyIconError> {
+        let id = match self.id {
+            Some(0) => return Err(NotifyIconError::ZeroId),
+            Some(id) => id,
+            None => return Err(NotifyIconError::MissingId),
+        };
+        if window.nicon(id).is_some() {
+            return Err(NotifyIconError::DuplicateId(id));
+        }
         unsafe {
             let mut nid: NOTIFYICONDATAW = zeroed();
             nid.cbSize = size_of::<NOTIFYICONDATAW>() as u32;
             nid.hWnd = window.as_raw();
-            nid.uCallbackMessage = WM_APP_NOTIFICATION_ICON;
-            nid.uID = self.id.unwrap() as u32;
-            if let Some(icon) = self.icon {
+            nid.uCallbackMessage = match self.version {
+                NotifyIconVersion::Version3 => WM_APP_NOTIFICATION_ICON_V3,
+                NotifyIconVersion::Version4 => WM_APP_NOTIFICATION_ICON,
+            };
+            nid.uID = id as u32;
+            if let Some(guid) = self.guid {
+                nid.uFlags |= NIF_GUID;
+                nid.guidItem = guid;
+            }
+            nid.dwStateMask = NIS_HIDDEN | NIS_SHAREDICON;
+            if self.hidden { nid.dwState |= NIS_HIDDEN }
+            if self.shared_icon { nid.dwState |= NIS_SHAREDICON }
+            if nid.dwState != 0 {
+                nid.uFlags |= NIF_STATE;
+            }
+            if let Some(ref icon) = self.icon {
+                nid.uFlags |= NIF_ICON;
+                nid.hIcon = icon.as_raw();
+            }
+            if let Some(ref themed) = self.themed {
                 nid.uFlags |= NIF_ICON;
-                nid.hIcon = icon.into_raw();
+                nid.hIcon = themed.select()?;
             }
-            *nid.u.uVersion_mut() = NOTIFYICON_VERSION_4;
+            *nid.u.uVersion_mut() = self.version.as_raw();
             Error::clear();
             let err = Shell_NotifyIconW(NIM_ADD, &mut nid);
             if err == 0 {
-                return Err(Error::get_last_error());
+                return Err(Error::get_last_error().into());
             }
             let ni = NotifyIcon(Rc::new(NotifyIconInternal {
                 nid: RefCell::new(nid),
                 handler: self.handler.unwrap_or_else(|| Box::new(|_, _| None)),
+                version: self.version,
+                icon: RefCell::new(self.icon),
+                themed: RefCell::new(self.themed),
+                animation: RefCell::new(None),
+                balloon_icon: RefCell::new(None),
+                removed: Cell::new(false),
+                context_menu: RefCell::new(self.context_menu),
+                default_item: self.default_item,
             }));
             ni.set_version()?;
             ni.enable_messages()?;
-            window.add_nicon(ni.clone(), ni.id());
+            window.add_nicon(&ni);
             Ok(ni)
         }
     }
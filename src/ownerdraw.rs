@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use winapi::shared::minwindef::TRUE;
+use winapi::shared::windef::{HDC, RECT};
+use winapi::um::wingdi::{RGB, SetBkMode, SetTextColor, TRANSPARENT};
+use winapi::um::winuser::{
+    COLOR_GRAYTEXT, COLOR_HIGHLIGHT, COLOR_HIGHLIGHTTEXT, COLOR_MENU, COLOR_MENUTEXT,
+    DRAWITEMSTRUCT, DT_LEFT, DT_SINGLELINE, DT_VCENTER, DrawTextW, FillRect, GetSysColor,
+    GetSysColorBrush, MEASUREITEMSTRUCT, ODS_CHECKED, ODS_DEFAULT, ODS_DISABLED, ODS_GRAYED,
+    ODS_SELECTED, ODT_MENU,
+};
+
+use Error;
+use brush::Brush;
+use event::EventResponse;
+use geometry::Rect;
+use wide::ToWide;
+use window::Window;
+
+// Owner drawn menu items carry a token in their item data which identifies their closures here.
+// The token is the address of the closures, so that owner drawn items from other code, which may
+// well hold small numbers there, are never mistaken for ours. It is only ever looked up, never
+// dereferenced. Like command ids, tokens are per thread as menus can't be used from other threads
+// anyway.
+thread_local!{
+    static OWNER_DRAWN: RefCell<HashMap<usize, Rc<OwnerDrawn>>> = RefCell::new(HashMap::new());
+}
+pub(crate) fn register(item: OwnerDrawn) -> usize {
+    let item = Rc::new(item);
+    let token = &*item as *const OwnerDrawn as usize;
+    OWNER_DRAWN.with(|table| table.borrow_mut().insert(token, item));
+    token
+}
+pub(crate) fn unregister(token: usize) {
+    OWNER_DRAWN.with(|table| {
+        table.borrow_mut().remove(&token);
+    });
+}
+fn get(token: usize) -> Option<Rc<OwnerDrawn>> {
+    OWNER_DRAWN.with(|table| table.borrow().get(&token).cloned())
+}
+// Called by the window for WM_MEASUREITEM. Returns None for anything that isn't one of our items.
+pub(crate) unsafe fn measure(
+    mis: *mut MEASUREITEMSTRUCT, window: &Window,
+) -> Option<EventResponse> {
+    let mis = &mut *mis;
+    if mis.CtlType != ODT_MENU {
+        return None;
+    }
+    let item = get(mis.itemData)?;
+    let mut size = ItemSize {
+        width: mis.itemWidth,
+        height: mis.itemHeight,
+    };
+    (*item.measure.borrow_mut())(&mut size, window);
+    mis.itemWidth = size.width;
+    mis.itemHeight = size.height;
+    Some(EventResponse::from_raw(TRUE as isize))
+}
+// Called by the window for WM_DRAWITEM. Returns None for anything that isn't one of our items.
+pub(crate) unsafe fn draw(dis: *const DRAWITEMSTRUCT, window: &Window) -> Option<EventResponse> {
+    let dis = &*dis;
+    if dis.CtlType != ODT_MENU {
+        return None;
+    }
+    let item = get(dis.itemData)?;
+    let r = dis.rcItem;
+    let ctx = DrawItem {
+        dc: dis.hDC,
+        rect: Rect::new(r.left, r.top, r.right, r.bottom),
+        state: ItemState::from_raw(dis.itemState),
+    };
+    (*item.draw.borrow_mut())(&ctx, window);
+    Some(EventResponse::from_raw(TRUE as isize))
+}
+type MeasureHandler = Box<dyn FnMut(&mut ItemSize, &Window)>;
+type DrawHandler = Box<dyn FnMut(&DrawItem, &Window)>;
+// The closures for an owner drawn item, kept alive for as long as the menu holding the item.
+pub struct OwnerDrawn {
+    measure: RefCell<MeasureHandler>,
+    draw: RefCell<DrawHandler>,
+}
+impl OwnerDrawn {
+    pub fn new<M, D>(measure: M, draw: D) -> OwnerDrawn
+        where M: FnMut(&mut ItemSize, &Window) + 'static, D: FnMut(&DrawItem, &Window) + 'static
+    {
+        OwnerDrawn {
+            measure: RefCell::new(Box::new(measure)),
+            draw: RefCell::new(Box::new(draw)),
+        }
+    }
+}
+// The size of the item, which the measure closure fills in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ItemSize {
+    pub width: u32,
+    pub height: u32,
+}
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ItemState {
+    // Highlighted, either by hovering with the mouse or with the keyboard.
+    pub selected: bool,
+    pub disabled: bool,
+    pub grayed: bool,
+    pub checked: bool,
+    pub default: bool,
+}
+impl ItemState {
+    fn from_raw(state: u32) -> ItemState {
+        ItemState {
+            selected: state & ODS_SELECTED != 0,
+            disabled: state & ODS_DISABLED != 0,
+            grayed: state & ODS_GRAYED != 0,
+            checked: state & ODS_CHECKED != 0,
+            default: state & ODS_DEFAULT != 0,
+        }
+    }
+}
+// What the draw closure gets to paint with. The device context is only valid for the duration of
+// the closure.
+pub struct DrawItem {
+    dc: HDC,
+    rect: Rect,
+    state: ItemState,
+}
+impl DrawItem {
+    pub fn dc(&self) -> HDC {
+        self.dc
+    }
+    // The area of the item, which is all that should be painted.
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+    pub fn state(&self) -> ItemState {
+        self.state
+    }
+    // Paints the background and sets the text color the way a regular item in this state would.
+    pub fn draw_background(&self) -> Result<(), Error> {
+        let (back, text) = if self.state.selected {
+            (COLOR_HIGHLIGHT, COLOR_HIGHLIGHTTEXT)
+        } else {
+            (COLOR_MENU, COLOR_MENUTEXT)
+        };
+        let text = if self.state.disabled || self.state.grayed { COLOR_GRAYTEXT } else { text };
+        let rect = raw_rect(self.rect);
+        unsafe {
+            if FillRect(self.dc, &rect, GetSysColorBrush(back)) == 0 {
+                return Err(Error::get_last_error());
+            }
+            SetTextColor(self.dc, GetSysColor(text));
+        }
+        Ok(())
+    }
+    pub fn fill_rect(&self, rect: Rect, brush: &Brush) -> Result<(), Error> {
+        if unsafe { FillRect(self.dc, &raw_rect(rect), brush.as_raw()) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
+    }
+    pub fn fill_rgb(&self, rect: Rect, r: u8, g: u8, b: u8) -> Result<(), Error> {
+        self.fill_rect(rect, &Brush::solid_rgb(r, g, b)?)
+    }
+    pub fn set_text_rgb(&self, r: u8, g: u8, b: u8) {
+        unsafe { SetTextColor(self.dc, RGB(r, g, b)) };
+    }
+    // Draws a single line of text vertically centered in the rectangle.
+    pub fn draw_text(&self, text: &str, rect: Rect) -> Result<(), Error> {
+        let text = text.to_wide();
+        let mut rect = raw_rect(rect);
+        unsafe {
+            SetBkMode(self.dc, TRANSPARENT as i32);
+            if DrawTextW(
+                self.dc, text.as_ptr(), text.len() as i32, &mut rect,
+                DT_LEFT | DT_SINGLELINE | DT_VCENTER,
+            ) == 0 {
+                return Err(Error::get_last_error());
+            }
+        }
+        Ok(())
+    }
+}
+fn raw_rect(r: Rect) -> RECT {
+    RECT { left: r.left, top: r.top, right: r.right, bottom: r.bottom }
+}
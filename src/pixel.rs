@@ -0,0 +1,103 @@
+// Conversions between the pixel layouts of images and icons.
+
+// The length in bytes of a row of a monochrome bitmap, which is padded to 16 bits. Rounded up by
+// hand, as `div_ceil` needs a newer compiler than this crate otherwise does.
+#[allow(clippy::manual_div_ceil)]
+pub fn mask_stride(width: u32) -> usize {
+    (width as usize + 15) / 16 * 2
+}
+// Builds the AND mask of an icon from the alpha of top-down RGBA or BGRA pixels, as rows of one
+// bit per pixel, which is what CreateBitmap takes. Set bits are transparent.
+pub fn and_mask(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    let stride = mask_stride(width);
+    let mut mask = vec![0u8; stride * height as usize];
+    for (i, p) in pixels.chunks(4).enumerate() {
+        if p[3] == 0 {
+            let (x, y) = (i % width as usize, i / width as usize);
+            mask[y * stride + x / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    mask
+}
+// Turns RGBA pixels into BGRA ones, and back.
+pub fn swap_red_blue(pixels: &mut [u8]) {
+    for p in pixels.chunks_mut(4) {
+        p.swap(0, 2);
+    }
+}
+// Whether any pixel of the BGRA or RGBA pixels isn't fully transparent. Bitmaps without an alpha
+// channel read back with every alpha at zero.
+pub fn has_alpha(pixels: &[u8]) -> bool {
+    pixels.chunks(4).any(|p| p[3] != 0)
+}
+// Sets the alpha of BGRA pixels from an icon mask read back as BGRA, in which black marks the
+// opaque pixels and white the transparent ones.
+pub fn alpha_from_mask(pixels: &mut [u8], mask: &[u8]) {
+    for (p, m) in pixels.chunks_mut(4).zip(mask.chunks(4)) {
+        p[3] = if m[0] == 0 && m[1] == 0 && m[2] == 0 { 255 } else { 0 };
+    }
+}
+// Multiplies the color of BGRA or RGBA pixels by their alpha, rounding to the nearest value.
+pub fn premultiply_alpha(pixels: &mut [u8]) {
+    for p in pixels.chunks_mut(4) {
+        let a = p[3] as u32;
+        for c in &mut p[..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLEAR: [u8; 4] = [1, 2, 3, 0];
+    const SOLID: [u8; 4] = [1, 2, 3, 255];
+
+    fn image(alphas: &[bool]) -> Vec<u8> {
+        alphas.iter().flat_map(|&solid| if solid { SOLID } else { CLEAR }).collect()
+    }
+
+    #[test]
+    fn stride() {
+        assert_eq!(mask_stride(1), 2);
+        assert_eq!(mask_stride(16), 2);
+        assert_eq!(mask_stride(17), 4);
+        assert_eq!(mask_stride(32), 4);
+    }
+    #[test]
+    fn mask() {
+        assert_eq!(and_mask(2, 2, &image(&[true, false, false, true])), vec![0x40, 0, 0x80, 0]);
+        // Partial transparency is left to the alpha channel.
+        assert_eq!(and_mask(1, 1, &[0, 0, 0, 1]), vec![0, 0]);
+        // Rows of 18 pixels take 3 bytes, padded to 4.
+        let mut alphas = vec![true; 18 * 2];
+        alphas[8] = false;
+        alphas[18 + 17] = false;
+        assert_eq!(and_mask(18, 2, &image(&alphas)), vec![0, 0x80, 0, 0, 0, 0, 0x40, 0]);
+        assert_eq!(and_mask(3, 1, &image(&[false; 3])), vec![0xE0, 0]);
+    }
+    #[test]
+    fn swap() {
+        let mut pixels = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        swap_red_blue(&mut pixels);
+        assert_eq!(pixels, vec![3, 2, 1, 4, 7, 6, 5, 8]);
+        swap_red_blue(&mut pixels);
+        assert_eq!(pixels, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+    #[test]
+    fn alpha() {
+        assert!(!has_alpha(&[1, 2, 3, 0, 4, 5, 6, 0]));
+        assert!(has_alpha(&[1, 2, 3, 0, 4, 5, 6, 1]));
+        assert!(!has_alpha(&[]));
+        let mut pixels = vec![1, 2, 3, 0, 4, 5, 6, 0];
+        alpha_from_mask(&mut pixels, &[0, 0, 0, 0, 255, 255, 255, 0]);
+        assert_eq!(pixels, vec![1, 2, 3, 255, 4, 5, 6, 0]);
+    }
+    #[test]
+    fn premultiply() {
+        let mut pixels = vec![200, 100, 255, 255, 200, 100, 255, 128, 200, 100, 255, 0];
+        premultiply_alpha(&mut pixels);
+        assert_eq!(pixels, vec![200, 100, 255, 255, 100, 50, 128, 128, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,142 @@
+use geometry::Rect;
+
+// The screen edge the taskbar is docked to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Left,
+    Top,
+    Right,
+    Bottom,
+}
+// Places a window of the given size beside the taskbar, the way the system flyouts are. It sits
+// `gap` away from the taskbar side of the work area, is centered on the anchor along the taskbar,
+// and is pushed back inside the work area where it would stick out. A window larger than the work
+// area is aligned to its top left corner.
+pub fn place_flyout(
+    anchor: Rect, width: i32, height: i32, edge: Edge, work_area: Rect, gap: i32,
+) -> Rect {
+    let center_x = anchor.left + anchor.width() / 2;
+    let center_y = anchor.top + anchor.height() / 2;
+    let (left, top) = match edge {
+        Edge::Bottom => (center_x - width / 2, work_area.bottom - gap - height),
+        Edge::Top => (center_x - width / 2, work_area.top + gap),
+        Edge::Left => (work_area.left + gap, center_y - height / 2),
+        Edge::Right => (work_area.right - gap - width, center_y - height / 2),
+    };
+    let left = clamp(left, work_area.left + gap, work_area.right - gap - width);
+    let top = clamp(top, work_area.top + gap, work_area.bottom - gap - height);
+    Rect::new(left, top, left + width, top + height)
+}
+// Like `Ord::clamp`, except that `min` wins when the range is empty.
+fn clamp(value: i32, min: i32, max: i32) -> i32 {
+    value.min(max).max(min)
+}
+// Works out the edge from where the taskbar is on the screen, for when the shell can't say.
+pub fn taskbar_edge(taskbar: Rect, screen: Rect) -> Edge {
+    let horizontal = taskbar.width() >= taskbar.height();
+    match (horizontal, taskbar.top - screen.top <= screen.bottom - taskbar.bottom,
+        taskbar.left - screen.left <= screen.right - taskbar.right)
+    {
+        (true, true, _) => Edge::Top,
+        (true, false, _) => Edge::Bottom,
+        (false, _, true) => Edge::Left,
+        (false, _, false) => Edge::Right,
+    }
+}
+// Guesses where the taskbar is from the part of the monitor that the work area leaves out, for
+// when the shell can't be asked. An auto-hidden taskbar leaves nothing out, and is taken to be an
+// empty strip along the bottom.
+pub fn taskbar_from_work_area(monitor: Rect, work_area: Rect) -> (Edge, Rect) {
+    let (m, w) = (monitor, work_area);
+    if w.bottom < m.bottom {
+        (Edge::Bottom, Rect::new(m.left, w.bottom, m.right, m.bottom))
+    } else if w.top > m.top {
+        (Edge::Top, Rect::new(m.left, m.top, m.right, w.top))
+    } else if w.left > m.left {
+        (Edge::Left, Rect::new(m.left, m.top, w.left, m.bottom))
+    } else if w.right < m.right {
+        (Edge::Right, Rect::new(w.right, m.top, m.right, m.bottom))
+    } else {
+        (Edge::Bottom, Rect::new(m.left, m.bottom, m.right, m.bottom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: Rect = Rect { left: 0, top: 0, right: 1920, bottom: 1080 };
+
+    fn icon(x: i32, y: i32) -> Rect {
+        Rect::new(x, y, x + 24, y + 24)
+    }
+
+    #[test]
+    fn bottom() {
+        let work_area = Rect::new(0, 0, 1920, 1040);
+        let rect = place_flyout(icon(1700, 1048), 300, 400, Edge::Bottom, work_area, 12);
+        assert_eq!(rect, Rect::new(1562, 628, 1862, 1028));
+    }
+    #[test]
+    fn top() {
+        let work_area = Rect::new(0, 40, 1920, 1080);
+        let rect = place_flyout(icon(1700, 8), 300, 400, Edge::Top, work_area, 12);
+        assert_eq!(rect, Rect::new(1562, 52, 1862, 452));
+    }
+    #[test]
+    fn left() {
+        let work_area = Rect::new(60, 0, 1920, 1080);
+        let rect = place_flyout(icon(18, 500), 300, 400, Edge::Left, work_area, 12);
+        assert_eq!(rect, Rect::new(72, 312, 372, 712));
+    }
+    #[test]
+    fn right() {
+        let work_area = Rect::new(0, 0, 1860, 1080);
+        let rect = place_flyout(icon(1878, 500), 300, 400, Edge::Right, work_area, 12);
+        assert_eq!(rect, Rect::new(1548, 312, 1848, 712));
+    }
+    #[test]
+    fn near_corners() {
+        // Centering on icons at the ends of the taskbar would push the flyout off the screen.
+        let work_area = Rect::new(0, 0, 1920, 1040);
+        let rect = place_flyout(icon(1890, 1048), 300, 400, Edge::Bottom, work_area, 12);
+        assert_eq!(rect, Rect::new(1608, 628, 1908, 1028));
+        let rect = place_flyout(icon(2, 1048), 300, 400, Edge::Bottom, work_area, 12);
+        assert_eq!(rect, Rect::new(12, 628, 312, 1028));
+        let work_area = Rect::new(60, 0, 1920, 1080);
+        let rect = place_flyout(icon(18, 1050), 300, 400, Edge::Left, work_area, 12);
+        assert_eq!(rect, Rect::new(72, 668, 372, 1068));
+        // Monitors left of the primary one have negative coordinates.
+        let work_area = Rect::new(-1920, 0, 0, 1040);
+        let rect = place_flyout(icon(-20, 1048), 300, 400, Edge::Bottom, work_area, 12);
+        assert_eq!(rect, Rect::new(-312, 628, -12, 1028));
+    }
+    #[test]
+    fn larger_than_work_area() {
+        let work_area = Rect::new(0, 0, 800, 560);
+        let rect = place_flyout(icon(700, 568), 1000, 700, Edge::Bottom, work_area, 12);
+        assert_eq!(rect, Rect::new(12, 12, 1012, 712));
+        let rect = place_flyout(icon(700, 568), 1000, 700, Edge::Top, work_area, 12);
+        assert_eq!(rect, Rect::new(12, 12, 1012, 712));
+    }
+    #[test]
+    fn edges() {
+        assert_eq!(taskbar_edge(Rect::new(0, 1040, 1920, 1080), SCREEN), Edge::Bottom);
+        assert_eq!(taskbar_edge(Rect::new(0, 0, 1920, 40), SCREEN), Edge::Top);
+        assert_eq!(taskbar_edge(Rect::new(0, 0, 60, 1080), SCREEN), Edge::Left);
+        assert_eq!(taskbar_edge(Rect::new(1860, 0, 1920, 1080), SCREEN), Edge::Right);
+    }
+    #[test]
+    fn from_work_area() {
+        let cases = [
+            (Rect::new(0, 0, 1920, 1040), Edge::Bottom, Rect::new(0, 1040, 1920, 1080)),
+            (Rect::new(0, 40, 1920, 1080), Edge::Top, Rect::new(0, 0, 1920, 40)),
+            (Rect::new(60, 0, 1920, 1080), Edge::Left, Rect::new(0, 0, 60, 1080)),
+            (Rect::new(0, 0, 1860, 1080), Edge::Right, Rect::new(1860, 0, 1920, 1080)),
+            (SCREEN, Edge::Bottom, Rect::new(0, 1080, 1920, 1080)),
+        ];
+        for &(work_area, edge, taskbar) in &cases {
+            assert_eq!(taskbar_from_work_area(SCREEN, work_area), (edge, taskbar));
+        }
+    }
+}
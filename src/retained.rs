@@ -0,0 +1,162 @@
+use std::mem::replace;
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+
+use Error;
+use menu::{Menu, MenuAction, MenuCheck, MenuItem, PopupMenu};
+use menumodel::{MenuModel, ModelItem, Patch};
+
+// A popup menu kept in sync with a `MenuModel`. Updating it only touches the items that changed,
+// so it doesn't flicker and any open submenu stays open.
+pub struct RetainedMenu {
+    menu: PopupMenu,
+    model: MenuModel,
+}
+impl RetainedMenu {
+    pub fn new(model: MenuModel) -> Result<RetainedMenu, Error> {
+        let mut retained = RetainedMenu {
+            menu: PopupMenu::new()?,
+            model: MenuModel::new(),
+        };
+        retained.update(model)?;
+        Ok(retained)
+    }
+    pub fn menu(&self) -> &PopupMenu {
+        &self.menu
+    }
+    pub fn model(&self) -> &MenuModel {
+        &self.model
+    }
+    // If a patch fails the menu is built again from the old model, so that it keeps matching
+    // `model`, and if even that fails it is left empty along with the model. A model with
+    // duplicate keys is an invalid parameter, see `MenuModel::check_keys`.
+    pub fn update(&mut self, model: MenuModel) -> Result<(), Error> {
+        let patches = self.model.diff(&model)
+            .map_err(|_| Error::from_raw(ERROR_INVALID_PARAMETER))?;
+        for patch in &patches {
+            if let Err(err) = apply_patch(&self.menu, patch) {
+                let old = replace(&mut self.model, MenuModel::new());
+                if self.rebuild(&old).is_ok() {
+                    self.model = old;
+                }
+                return Err(err);
+            }
+        }
+        self.model = model;
+        Ok(())
+    }
+    // Fills the menu from scratch, leaving it empty on failure.
+    fn rebuild(&self, model: &MenuModel) -> Result<(), Error> {
+        self.menu.clear()?;
+        for (i, item) in model.items.iter().enumerate() {
+            if let Err(err) = insert(&self.menu, i as u32, item) {
+                self.menu.clear()?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+pub fn apply_patch(menu: &Menu, patch: &Patch) -> Result<(), Error> {
+    let path = match *patch {
+        Patch::Remove { ref path, .. } => path,
+        Patch::Insert { ref path, .. } => path,
+        Patch::Modify { ref path, .. } => path,
+    };
+    apply_at(menu, path, patch)
+}
+fn apply_at(menu: &Menu, path: &[u32], patch: &Patch) -> Result<(), Error> {
+    match path.split_first() {
+        Some((&first, rest)) => menu.with_submenu(first, |submenu| apply_at(submenu, rest, patch))?,
+        None => apply_here(menu, patch),
+    }
+}
+fn apply_here(menu: &Menu, patch: &Patch) -> Result<(), Error> {
+    match *patch {
+        Patch::Remove { position, .. } => menu.delete(MenuItem::Position(position)),
+        Patch::Insert { position, ref item, .. } => insert(menu, position, item),
+        Patch::Modify { position, ref label, status, check, .. } => {
+            let item = MenuItem::Position(position);
+            if let Some(ref label) = *label {
+                menu.set_label(item, label)?;
+            }
+            if let Some(status) = status {
+                menu.set_status(item, status)?;
+            }
+            if let Some(check) = check {
+                menu.set_check(item, check)?;
+            }
+            Ok(())
+        },
+    }
+}
+fn insert(menu: &Menu, position: u32, item: &ModelItem) -> Result<(), Error> {
+    let before = MenuItem::Position(position);
+    match *item {
+        ModelItem::Command { ref label, id, status, check, .. } => {
+            menu.insert_string(before, label, MenuAction::Id(id), status, check)?;
+        },
+        ModelItem::Separator { .. } => menu.insert_separator(before)?,
+        ModelItem::Submenu { ref label, status, ref items, .. } => {
+            let child = PopupMenu::new()?;
+            for (i, item) in items.iter().enumerate() {
+                insert(&child, i as u32, item)?;
+            }
+            let action = MenuAction::ChildMenu(child);
+            menu.insert_string(before, label, action, status, MenuCheck::Unchecked)?;
+        },
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command;
+    use menumodel::MenuStatus;
+
+    fn command(key: &str, id: u16) -> ModelItem {
+        ModelItem::Command {
+            key: key.to_owned(),
+            label: key.to_owned(),
+            id: id,
+            status: MenuStatus::Enabled,
+            check: MenuCheck::Unchecked,
+        }
+    }
+    fn submenu(key: &str, items: Vec<ModelItem>) -> ModelItem {
+        ModelItem::Submenu {
+            key: key.to_owned(),
+            label: key.to_owned(),
+            status: MenuStatus::Enabled,
+            items: items,
+        }
+    }
+    fn nested(key: &str, id: u16) -> MenuModel {
+        MenuModel { items: vec![submenu("top", vec![submenu(key, vec![command(key, id)])])] }
+    }
+    #[test]
+    fn nested_updates_free_ids() {
+        let mut menu = RetainedMenu::new(nested("a", 1)).unwrap();
+        let count = command::count();
+        for _ in 0..10 {
+            menu.update(nested("b", 2)).unwrap();
+            menu.update(nested("a", 1)).unwrap();
+        }
+        assert_eq!(command::count(), count);
+        drop(menu);
+        assert_eq!(command::count(), 0);
+    }
+    #[test]
+    fn failed_update_rebuilds() {
+        let mut menu = RetainedMenu::new(nested("a", 1)).unwrap();
+        // Ids from the automatically allocated range are refused, halfway through the patches.
+        assert!(menu.update(nested("b", 0x8000)).is_err());
+        assert_eq!(menu.model(), &nested("a", 1));
+        let count = menu.menu().with_submenu(0, |top| {
+            top.with_submenu(0, |inner| inner.item_count()).unwrap().unwrap()
+        }).unwrap();
+        assert_eq!(count, 1);
+        menu.update(nested("c", 3)).unwrap();
+        drop(menu);
+        assert_eq!(command::count(), 0);
+    }
+}
@@ -0,0 +1,201 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+// Virtual key codes for keys that don't map to themselves.
+static NAMED_KEYS: &[(&str, u16)] = &[
+    ("Backspace", 0x08),
+    ("Tab", 0x09),
+    ("Enter", 0x0D),
+    ("Pause", 0x13),
+    ("Esc", 0x1B),
+    ("Space", 0x20),
+    ("PageUp", 0x21),
+    ("PageDown", 0x22),
+    ("End", 0x23),
+    ("Home", 0x24),
+    ("Left", 0x25),
+    ("Up", 0x26),
+    ("Right", 0x27),
+    ("Down", 0x28),
+    ("Insert", 0x2D),
+    ("Delete", 0x2E),
+    ("Num0", 0x60),
+    ("Num1", 0x61),
+    ("Num2", 0x62),
+    ("Num3", 0x63),
+    ("Num4", 0x64),
+    ("Num5", 0x65),
+    ("Num6", 0x66),
+    ("Num7", 0x67),
+    ("Num8", 0x68),
+    ("Num9", 0x69),
+    ("Multiply", 0x6A),
+    ("Add", 0x6B),
+    ("Subtract", 0x6D),
+    ("Decimal", 0x6E),
+    ("Divide", 0x6F),
+    (";", 0xBA),
+    ("+", 0xBB),
+    (",", 0xBC),
+    ("-", 0xBD),
+    (".", 0xBE),
+    ("/", 0xBF),
+    ("`", 0xC0),
+    ("[", 0xDB),
+    ("\\", 0xDC),
+    ("]", 0xDD),
+    ("'", 0xDE),
+];
+// Alternative spellings accepted when parsing.
+static KEY_ALIASES: &[(&str, &str)] = &[
+    ("Escape", "Esc"),
+    ("Return", "Enter"),
+    ("Del", "Delete"),
+    ("Ins", "Insert"),
+    ("PgUp", "PageUp"),
+    ("PgDn", "PageDown"),
+    ("Plus", "+"),
+    ("=", "+"),
+    ("Minus", "-"),
+    ("Comma", ","),
+    ("Period", "."),
+];
+
+// A key combination such as `Ctrl+Shift+S`. Parsing is case insensitive, and the modifiers may
+// come in any order, but they always display as Ctrl, Alt, Shift in that order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Shortcut {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    // The virtual key code.
+    pub key: u16,
+}
+impl Shortcut {
+    pub fn key_name(key: u16) -> Option<String> {
+        match key {
+            0x30..=0x39 | 0x41..=0x5A => Some((key as u8 as char).to_string()),
+            0x70..=0x87 => Some(format!("F{}", key - 0x70 + 1)),
+            _ => NAMED_KEYS.iter().find(|&&(_, k)| k == key).map(|&(name, _)| name.to_owned()),
+        }
+    }
+    fn parse_key(name: &str) -> Option<u16> {
+        let name = KEY_ALIASES.iter().find(|&&(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map(|&(_, name)| name).unwrap_or(name);
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if c.is_ascii_alphanumeric() {
+                return Some(c.to_ascii_uppercase() as u16);
+            }
+        }
+        if name.len() > 1 && (name.starts_with('F') || name.starts_with('f')) {
+            if let Ok(n) = name[1..].parse::<u16>() {
+                if (1..=24).contains(&n) {
+                    return Some(0x70 + n - 1);
+                }
+            }
+        }
+        NAMED_KEYS.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, k)| k)
+    }
+}
+impl FromStr for Shortcut {
+    type Err = ParseShortcutError;
+    fn from_str(s: &str) -> Result<Shortcut, ParseShortcutError> {
+        let err = || ParseShortcutError(s.to_owned());
+        let mut shortcut = Shortcut { ctrl: false, alt: false, shift: false, key: 0 };
+        // Splitting on '+' alone would make "Ctrl++" impossible, so the key is whatever follows
+        // the last modifier.
+        let mut rest = s.trim();
+        loop {
+            let split = match rest.find('+') {
+                Some(i) if i > 0 && i + 1 < rest.len() => i,
+                _ => break,
+            };
+            let modifier = rest[..split].trim();
+            if modifier.eq_ignore_ascii_case("Ctrl") || modifier.eq_ignore_ascii_case("Control") {
+                shortcut.ctrl = true;
+            } else if modifier.eq_ignore_ascii_case("Alt") {
+                shortcut.alt = true;
+            } else if modifier.eq_ignore_ascii_case("Shift") {
+                shortcut.shift = true;
+            } else {
+                return Err(err());
+            }
+            rest = rest[split + 1..].trim();
+        }
+        shortcut.key = Shortcut::parse_key(rest).ok_or_else(err)?;
+        Ok(shortcut)
+    }
+}
+impl fmt::Display for Shortcut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ctrl { f.write_str("Ctrl+")? }
+        if self.alt { f.write_str("Alt+")? }
+        if self.shift { f.write_str("Shift+")? }
+        match Shortcut::key_name(self.key) {
+            Some(name) => f.write_str(&name),
+            None => write!(f, "0x{:02X}", self.key),
+        }
+    }
+}
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseShortcutError(String);
+impl fmt::Display for ParseShortcutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid shortcut {:?}", self.0)
+    }
+}
+impl StdError for ParseShortcutError {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shortcut(ctrl: bool, alt: bool, shift: bool, key: u16) -> Shortcut {
+        Shortcut { ctrl: ctrl, alt: alt, shift: shift, key: key }
+    }
+    #[test]
+    fn round_trip() {
+        for key in 0..0x100 {
+            if Shortcut::key_name(key).is_none() {
+                continue;
+            }
+            for modifiers in 0..8 {
+                let s = shortcut(modifiers & 1 != 0, modifiers & 2 != 0, modifiers & 4 != 0, key);
+                let text = s.to_string();
+                assert_eq!(text.parse::<Shortcut>(), Ok(s), "{}", text);
+            }
+        }
+    }
+    #[test]
+    fn parse() {
+        let cases = [
+            ("Ctrl+S", shortcut(true, false, false, 0x53)),
+            ("shift + ctrl + f5", shortcut(true, false, true, 0x74)),
+            ("Alt+F24", shortcut(false, true, false, 0x87)),
+            ("Control+Escape", shortcut(true, false, false, 0x1B)),
+            ("Ctrl++", shortcut(true, false, false, 0xBB)),
+            ("Ctrl+=", shortcut(true, false, false, 0xBB)),
+            ("Ctrl+Minus", shortcut(true, false, false, 0xBD)),
+            ("+", shortcut(false, false, false, 0xBB)),
+            ("pgdn", shortcut(false, false, false, 0x22)),
+            ("7", shortcut(false, false, false, 0x37)),
+        ];
+        for &(text, expected) in &cases {
+            assert_eq!(text.parse::<Shortcut>(), Ok(expected), "{}", text);
+        }
+    }
+    #[test]
+    fn parse_invalid() {
+        for &text in &["", "Ctrl+", "Hyper+A", "F25", "F0", "Ctrl+Alt", "Ctrl+AB", "é"] {
+            let err = ParseShortcutError(text.to_owned());
+            assert_eq!(text.parse::<Shortcut>(), Err(err), "{}", text);
+        }
+    }
+    #[test]
+    fn display() {
+        assert_eq!(shortcut(true, true, true, 0x2E).to_string(), "Ctrl+Alt+Shift+Delete");
+        assert_eq!(shortcut(false, false, true, 0x70).to_string(), "Shift+F1");
+        assert_eq!(shortcut(true, false, false, 0xFF).to_string(), "Ctrl+0xFF");
+    }
+}
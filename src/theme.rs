@@ -0,0 +1,186 @@
+use std::mem::{size_of, transmute, zeroed};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use winapi::shared::minwindef::{BOOL, DWORD, FARPROC, HMODULE};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_NOT_SUPPORTED, ERROR_SUCCESS};
+use winapi::um::dwmapi::{DwmGetColorizationColor, DwmSetWindowAttribute};
+use winapi::um::libloaderapi::{
+    FreeLibrary, GetModuleHandleW, GetProcAddress, LOAD_LIBRARY_SEARCH_SYSTEM32, LoadLibraryExW,
+};
+use winapi::um::winnt::{LPCSTR, OSVERSIONINFOW};
+use winapi::um::winreg::{HKEY_CURRENT_USER, RRF_RT_REG_DWORD, RegGetValueW};
+
+use Error;
+use wide::ToWide;
+
+// Older builds of Windows 10 used 19 for this attribute before it was documented as 20.
+const DWMWA_USE_IMMERSIVE_DARK_MODE: DWORD = 20;
+const DWMWA_USE_IMMERSIVE_DARK_MODE_OLD: DWORD = 19;
+const PERSONALIZE_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize";
+// The setting area broadcast with WM_SETTINGCHANGE when the light/dark preference changes.
+pub const COLOR_SET_AREA: &str = "ImmersiveColorSet";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThemeMode {
+    Light,
+    Dark,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThemePreference {
+    pub apps: ThemeMode,
+    pub system: ThemeMode,
+}
+impl ThemePreference {
+    pub fn query() -> Result<ThemePreference, Error> {
+        Ok(ThemePreference {
+            apps: query_mode("AppsUseLightTheme")?,
+            system: query_mode("SystemUsesLightTheme")?,
+        })
+    }
+}
+fn query_mode(value: &str) -> Result<ThemeMode, Error> {
+    let mut data: DWORD = 1;
+    let mut len = size_of::<DWORD>() as DWORD;
+    let err = unsafe { RegGetValueW(
+        HKEY_CURRENT_USER,
+        PERSONALIZE_KEY.to_wide_null().as_ptr(),
+        value.to_wide_null().as_ptr(),
+        RRF_RT_REG_DWORD, null_mut(), &mut data as *mut DWORD as *mut _, &mut len,
+    )} as DWORD;
+    match err {
+        ERROR_SUCCESS => Ok(if data == 0 { ThemeMode::Dark } else { ThemeMode::Light }),
+        // Versions of Windows without a dark mode don't have the value at all.
+        ERROR_FILE_NOT_FOUND => Ok(ThemeMode::Light),
+        err => Err(Error::from_raw(err)),
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccentColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub opaque: bool,
+}
+impl AccentColor {
+    pub fn query() -> Result<AccentColor, Error> {
+        let mut color: DWORD = 0;
+        let mut opaque: BOOL = 0;
+        let hr = unsafe { DwmGetColorizationColor(&mut color, &mut opaque) };
+        if hr < 0 {
+            return Err(Error::from_raw(hr as u32));
+        }
+        Ok(AccentColor {
+            r: (color >> 16) as u8,
+            g: (color >> 8) as u8,
+            b: color as u8,
+            opaque: opaque != 0,
+        })
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppMode {
+    Default,
+    AllowDark,
+    ForceDark,
+    ForceLight,
+}
+// The first build with the undocumented dark mode exports, where ordinal 135 is still
+// AllowDarkModeForApp(BOOL) rather than SetPreferredAppMode.
+const BUILD_1809: DWORD = 17763;
+const BUILD_1903: DWORD = 18362;
+// The real build number, which GetVersionExW hides from programs without a manifest.
+fn build_number() -> DWORD {
+    static BUILD: AtomicUsize = AtomicUsize::new(0);
+    let mut build = BUILD.load(Ordering::Relaxed);
+    if build == 0 {
+        build = unsafe {
+            let ntdll = GetModuleHandleW("ntdll.dll".to_wide_null().as_ptr());
+            let func = GetProcAddress(ntdll, b"RtlGetVersion\0".as_ptr() as LPCSTR);
+            let mut info: OSVERSIONINFOW = zeroed();
+            info.dwOSVersionInfoSize = size_of::<OSVERSIONINFOW>() as DWORD;
+            if !func.is_null() {
+                type RtlGetVersion = unsafe extern "system" fn(*mut OSVERSIONINFOW) -> i32;
+                transmute::<FARPROC, RtlGetVersion>(func)(&mut info);
+            }
+            info.dwBuildNumber as usize
+        };
+        BUILD.store(build, Ordering::Relaxed);
+    }
+    build as DWORD
+}
+// Dark context menus are only reachable through undocumented uxtheme exports, which are only
+// exported by ordinal. They're looked up at runtime so that older versions of Windows merely
+// ignore the request instead of failing to load the program. The module stays loaded for good,
+// as the settings made through it do too.
+unsafe fn uxtheme_ordinal(ordinal: u16) -> Result<FARPROC, Error> {
+    static UXTHEME: AtomicUsize = AtomicUsize::new(0);
+    if build_number() < BUILD_1809 {
+        return Err(Error::from_raw(ERROR_NOT_SUPPORTED));
+    }
+    let mut module = UXTHEME.load(Ordering::Acquire) as HMODULE;
+    if module.is_null() {
+        module = LoadLibraryExW(
+            "uxtheme.dll".to_wide_null().as_ptr(), null_mut(), LOAD_LIBRARY_SEARCH_SYSTEM32,
+        );
+        if module.is_null() {
+            return Err(Error::get_last_error());
+        }
+        // Another thread may have been quicker, in which case its handle is kept.
+        match UXTHEME.compare_exchange(0, module as usize, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => (),
+            Err(other) => {
+                FreeLibrary(module);
+                module = other as HMODULE;
+            },
+        }
+    }
+    let func = GetProcAddress(module, ordinal as usize as LPCSTR);
+    if func.is_null() { Err(Error::get_last_error()) } else { Ok(func) }
+}
+// Affects every menu created by this process from now on. Use `AllowDark` together with
+// `Window::allow_dark_mode` to opt in per window, or `ForceDark` to always use dark menus.
+// Windows 10 1809 can only allow dark menus or not, so there `ForceDark` acts like `AllowDark`
+// and `ForceLight` like `Default`.
+pub fn set_preferred_app_mode(mode: AppMode) -> Result<(), Error> {
+    unsafe {
+        let func = uxtheme_ordinal(135)?;
+        if build_number() < BUILD_1903 {
+            let allow = transmute::<FARPROC, unsafe extern "system" fn(BOOL) -> BOOL>(func);
+            allow(match mode {
+                AppMode::AllowDark | AppMode::ForceDark => 1,
+                AppMode::Default | AppMode::ForceLight => 0,
+            });
+        } else {
+            let set = transmute::<FARPROC, unsafe extern "system" fn(i32) -> i32>(func);
+            set(mode as i32);
+        }
+        if let Ok(f) = uxtheme_ordinal(136) {
+            let flush = transmute::<FARPROC, unsafe extern "system" fn()>(f);
+            flush();
+        }
+    }
+    Ok(())
+}
+pub(crate) fn allow_dark_mode_for_window(hwnd: HWND, allow: bool) -> Result<(), Error> {
+    unsafe {
+        let func = uxtheme_ordinal(133)?;
+        let func = transmute::<FARPROC, unsafe extern "system" fn(HWND, bool) -> bool>(func);
+        func(hwnd, allow);
+    }
+    Ok(())
+}
+pub(crate) fn set_dark_title_bar(hwnd: HWND, dark: bool) -> Result<(), Error> {
+    let value: BOOL = dark as BOOL;
+    let set = |attr| unsafe { DwmSetWindowAttribute(
+        hwnd, attr, &value as *const BOOL as *const _, size_of::<BOOL>() as DWORD,
+    )};
+    let mut hr = set(DWMWA_USE_IMMERSIVE_DARK_MODE);
+    if hr < 0 {
+        hr = set(DWMWA_USE_IMMERSIVE_DARK_MODE_OLD);
+    }
+    if hr < 0 {
+        return Err(Error::from_raw(hr as u32));
+    }
+    Ok(())
+}
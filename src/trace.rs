@@ -0,0 +1,48 @@
+use std::time::Instant;
+use winapi::shared::minwindef::UINT;
+use winapi::shared::windef::HWND;
+
+use event::{Event, EventResponse};
+use msgname::describe_message;
+use window::Route;
+
+// Logs every message passing through the window procedure under the `uitest::trace` target. The
+// event is formatted up front because the handlers consume it.
+pub(crate) struct Trace {
+    hwnd: HWND,
+    msg: UINT,
+    event: Option<String>,
+    start: Instant,
+}
+impl Trace {
+    pub(crate) fn begin(hwnd: HWND, msg: UINT, event: &Event) -> Trace {
+        let event = if log_enabled!(target: "uitest::trace", ::log::Level::Trace) {
+            Some(format!("{:?}", event))
+        } else {
+            None
+        };
+        Trace {
+            hwnd: hwnd,
+            msg: msg,
+            event: event,
+            start: Instant::now(),
+        }
+    }
+    pub(crate) fn end(self, route: Route, response: &Option<EventResponse>) {
+        let event = match self.event {
+            Some(event) => event,
+            None => return,
+        };
+        let elapsed = self.start.elapsed();
+        let response = match *response {
+            Some(ref r) => format!("{}", r.as_raw()),
+            None => "DefWindowProc".to_owned(),
+        };
+        trace!(
+            target: "uitest::trace",
+            "{:p} {} {} -> {:?} -> {} in {}.{:06}s",
+            self.hwnd, describe_message(self.msg), event, route, response,
+            elapsed.as_secs(), elapsed.subsec_micros(),
+        );
+    }
+}
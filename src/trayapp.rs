@@ -0,0 +1,229 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::ptr::null_mut;
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::synchapi::CreateMutexW;
+use winapi::um::winnt::HANDLE;
+use winapi::um::winuser::DestroyWindow;
+
+use Error;
+use class::ClassBuilder;
+use event::{Event, EventResponse, NotifyIconEvent};
+use geometry::Rect;
+use icon::Icon;
+use instance::mutex_name;
+use menu::{MenuAction, MenuCheck, MenuItem, MenuStatus, PopupMenu};
+use notifyicon::{NotifyIcon, NotifyIconBuilder, NotifyIconError};
+use wide::ToWide;
+use window::{Window, WindowBuilder};
+use wndproc::message_loop;
+
+#[derive(Debug)]
+pub enum TrayAppError {
+    // Another instance holds the single instance lock.
+    AlreadyRunning,
+    NotifyIcon(NotifyIconError),
+    Windows(Error),
+}
+impl From<Error> for TrayAppError {
+    fn from(err: Error) -> TrayAppError {
+        TrayAppError::Windows(err)
+    }
+}
+impl From<NotifyIconError> for TrayAppError {
+    fn from(err: NotifyIconError) -> TrayAppError {
+        TrayAppError::NotifyIcon(err)
+    }
+}
+impl fmt::Display for TrayAppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrayAppError::AlreadyRunning => f.write_str("another instance is already running"),
+            TrayAppError::NotifyIcon(ref err) => write!(f, "{}", err),
+            TrayAppError::Windows(err) => {
+                write!(f, "tray app setup failed: error {}", err.as_raw())
+            },
+        }
+    }
+}
+impl StdError for TrayAppError {}
+// A named mutex held for as long as the app runs.
+struct SingleInstance(HANDLE);
+impl SingleInstance {
+    fn acquire(name: &str) -> Result<SingleInstance, TrayAppError> {
+        let name = mutex_name(name).to_wide_null();
+        let handle = unsafe { CreateMutexW(null_mut(), FALSE, name.as_ptr()) };
+        if handle.is_null() {
+            return Err(Error::get_last_error().into());
+        }
+        if Error::get_last_error().as_raw() == ERROR_ALREADY_EXISTS {
+            unsafe { CloseHandle(handle) };
+            return Err(TrayAppError::AlreadyRunning);
+        }
+        Ok(SingleInstance(handle))
+    }
+}
+impl Drop for SingleInstance {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+// Everything a tray app needs, wired together: a hidden window, the notification icon with its
+// menu and a quit item, and the message loop.
+pub struct TrayApp {
+    name: String,
+    single_instance: bool,
+    window: WindowBuilder,
+    icon: IconSetup,
+}
+struct IconSetup {
+    builder: NotifyIconBuilder,
+    tooltip: Option<String>,
+    menu: Option<PopupMenu>,
+    quit_label: String,
+}
+impl TrayApp {
+    // The name is used for the window class and the single instance lock, so it should be unique
+    // to the app.
+    pub fn new(name: &str) -> TrayApp {
+        TrayApp {
+            name: name.to_owned(),
+            single_instance: false,
+            window: WindowBuilder::new(),
+            icon: IconSetup {
+                builder: NotifyIconBuilder::new().id(1),
+                tooltip: None,
+                menu: None,
+                quit_label: "E&xit".to_owned(),
+            },
+        }
+    }
+    pub fn icon(mut self, icon: Icon) -> TrayApp {
+        self.icon.builder = self.icon.builder.icon(icon);
+        self
+    }
+    pub fn tooltip(mut self, tooltip: &str) -> TrayApp {
+        self.icon.tooltip = Some(tooltip.to_owned());
+        self
+    }
+    // The quit item is added at the bottom, below a separator.
+    pub fn menu(mut self, menu: PopupMenu) -> TrayApp {
+        self.icon.menu = Some(menu);
+        self
+    }
+    // See `NotifyIconBuilder::default_item`.
+    pub fn default_item(mut self, item: MenuItem) -> TrayApp {
+        self.icon.builder = self.icon.builder.default_item(item);
+        self
+    }
+    pub fn quit_label(mut self, label: &str) -> TrayApp {
+        self.icon.quit_label = label.to_owned();
+        self
+    }
+    // Makes `run` fail with `TrayAppError::AlreadyRunning` while another instance is running.
+    pub fn single_instance(mut self, single: bool) -> TrayApp {
+        self.single_instance = single;
+        self
+    }
+    // Gets the icon events not taken care of by the menu.
+    pub fn handler<T>(
+        mut self, handler: T
+    ) -> TrayApp where T: Fn(NotifyIconEvent, &Window) -> Option<EventResponse> + 'static {
+        self.icon.builder = self.icon.builder.handler(handler);
+        self
+    }
+    // Gets the events of the hidden window, including `Event::MenuCommand` for menu items without
+    // a callback.
+    pub fn window_handler<T>(
+        mut self, handler: T
+    ) -> TrayApp where T: Fn(Event, &Window) -> Option<EventResponse> + Send + 'static {
+        self.window = self.window.handler(handler);
+        self
+    }
+    // Runs the app on the current thread until the user quits.
+    pub fn run(self) -> Result<(), TrayAppError> {
+        let _instance = if self.single_instance {
+            Some(SingleInstance::acquire(&self.name)?)
+        } else {
+            None
+        };
+        let class = ClassBuilder::new().name(&self.name).register()?;
+        // A top level window rather than a message only one, as those don't get told when Explorer
+        // restarts. It is never shown.
+        let window = self.window.class(class).create_local(Rect::default())?;
+        let _icon = match self.icon.create(&window) {
+            Ok(icon) => icon,
+            Err(err) => {
+                unsafe { DestroyWindow(window.as_raw()) };
+                return Err(err);
+            },
+        };
+        window.set_quit_on_destroy(true);
+        message_loop();
+        Ok(())
+    }
+}
+impl IconSetup {
+    fn create(self, window: &Window) -> Result<NotifyIcon, TrayAppError> {
+        let menu = with_quit_item(self.menu, &self.quit_label)?;
+        let icon = self.builder.context_menu(menu).create(window)?;
+        if let Some(ref tooltip) = self.tooltip {
+            icon.set_tooltip(tooltip)?;
+        }
+        Ok(icon)
+    }
+}
+// Adds the quit item at the bottom of the menu, below a separator unless it is the only item.
+fn with_quit_item(menu: Option<PopupMenu>, label: &str) -> Result<PopupMenu, Error> {
+    let menu = match menu {
+        Some(menu) => {
+            if menu.item_count()? > 0 {
+                menu.append_separator()?;
+            }
+            menu
+        },
+        None => PopupMenu::new()?,
+    };
+    let quit = MenuAction::callback(|window| {
+        let _ = window.as_ref().close();
+    });
+    menu.append_string(label, quit, MenuStatus::Enabled, MenuCheck::Unchecked)?;
+    Ok(menu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let app = TrayApp::new("Tray Test");
+        assert_eq!(app.name, "Tray Test");
+        assert!(!app.single_instance);
+        assert_eq!(app.icon.quit_label, "E&xit");
+        assert!(app.icon.tooltip.is_none() && app.icon.menu.is_none());
+        let app = app.single_instance(true).tooltip("Tip").quit_label("&Quit")
+            .menu(PopupMenu::new().unwrap());
+        assert!(app.single_instance);
+        assert_eq!(app.icon.tooltip.as_ref().map(|s| &s[..]), Some("Tip"));
+        assert_eq!(app.icon.quit_label, "&Quit");
+        assert!(app.icon.menu.is_some());
+    }
+    #[test]
+    fn quit_item() {
+        let menu = with_quit_item(None, "E&xit").unwrap();
+        assert_eq!(menu.item_count().unwrap(), 1);
+        let info = menu.item_info(MenuItem::Position(0)).unwrap();
+        assert_eq!(info.text.as_ref().map(|s| &s[..]), Some("E&xit"));
+        let menu = PopupMenu::new().unwrap();
+        menu.append_string("&Open", MenuAction::Id(1), MenuStatus::Enabled, MenuCheck::Unchecked)
+            .unwrap();
+        let menu = with_quit_item(Some(menu), "&Quit").unwrap();
+        assert_eq!(menu.item_count().unwrap(), 3);
+        assert!(menu.item_info(MenuItem::Position(1)).unwrap().separator);
+        let info = menu.item_info(MenuItem::Position(2)).unwrap();
+        assert_eq!(info.text.as_ref().map(|s| &s[..]), Some("&Quit"));
+    }
+}
@@ -3,6 +3,7 @@
 use std::ffi::{OsStr, OsString};
 use std::os::windows::ffi::{OsStrExt, OsStringExt};
 use std::path::{PathBuf};
+use std::slice::from_raw_parts;
 
 pub trait ToWide {
     fn to_wide(&self) -> Vec<u16>;
@@ -22,6 +23,13 @@ pub trait FromWide where Self: Sized {
         let len = wide.iter().take_while(|&&c| c != 0).count();
         Self::from_wide(&wide[..len])
     }
+    unsafe fn from_wide_ptr(ptr: *const u16) -> Self {
+        let mut len = 0;
+        while *ptr.offset(len) != 0 {
+            len += 1;
+        }
+        Self::from_wide(from_raw_parts(ptr, len as usize))
+    }
 }
 impl FromWide for OsString {
     fn from_wide(wide: &[u16]) -> OsString {
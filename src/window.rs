@@ -1,4 +1,4 @@
-use std::cell::{Cell, RefCell};
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::HashMap;
 use std::mem::forget;
 use std::ptr::null_mut;
@@ -7,16 +7,25 @@ use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::thread::spawn;
 use winapi::shared::minwindef::{LPARAM, UINT, WPARAM};
 use winapi::shared::windef::{HWND};
-use winapi::shared::winerror::ERROR_INVALID_WINDOW_HANDLE;
+use winapi::shared::winerror::{ERROR_BUSY, ERROR_INVALID_WINDOW_HANDLE};
 use winapi::um::winuser::{
-    CW_USEDEFAULT, CreateWindowExW, GetWindowLongPtrW, HWND_MESSAGE, PostMessageW, PostQuitMessage,
-    SetWindowLongPtrW, WM_CLOSE,
+    CW_USEDEFAULT, CreateWindowExW, DrawMenuBar, GetWindowLongPtrW, PostMessageW,
+    PostQuitMessage, SetMenu, SetWindowLongPtrW, WM_CLOSE, WS_EX_TOOLWINDOW, WS_EX_TOPMOST,
+    WS_POPUP,
 };
 
 use Error;
+use accel::{self, AcceleratorTable};
 use class::Class;
+use command;
 use event::{Event, EventResponse};
-use notifyicon::NotifyIcon;
+use geometry::Rect;
+use menu::{Menu, MenuBar, populate_lazy};
+use notifyicon::{NotifyIcon, WeakNotifyIcon, animated_icon};
+use ownerdraw;
+#[cfg(feature = "trace")]
+use trace::Trace;
+use theme::{COLOR_SET_AREA, allow_dark_mode_for_window, set_dark_title_bar};
 use wide::ToWide;
 use wndproc::message_loop;
 // Because we cannot assign state to the window until after it is created, and the window procedure
@@ -63,14 +72,25 @@ struct WindowInternal {
     hwnd: Arc<HWND>,
     handler: Box<Fn(Event, &Window) -> Option<EventResponse> + Send>,
     class: Cell<Option<Class>>,
-    nicons: RefCell<HashMap<u16, NotifyIcon>>,
-    menu_handler: Cell<Option<Box<FnMut(u16, &Window)>>>,
+    nicons: RefCell<HashMap<u16, WeakNotifyIcon>>,
+    menu_bar: RefCell<Option<MenuBar>>,
+    accelerators: RefCell<Option<AcceleratorTable>>,
+    // Whether the message loop of the thread ends along with the window.
+    quit_on_destroy: Cell<bool>,
 }
 impl Drop for WindowInternal {
     fn drop(&mut self) {
         // Might be something in the future
     }
 }
+// Which handler an event was dispatched to. Only ever read through `Debug` by the tracing layer.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Route {
+    Menu,
+    Destroy,
+    NotifyIcon,
+    Window,
+}
 pub struct Window(Rc<WindowInternal>);
 impl Window {
     pub(crate) fn initialize(hwnd: HWND) -> Result<Window, Error> {
@@ -82,7 +102,9 @@ impl Window {
             handler: handler,
             class: Cell::new(None),
             nicons: RefCell::new(HashMap::new()),
-            menu_handler: Cell::new(None),
+            menu_bar: RefCell::new(None),
+            accelerators: RefCell::new(None),
+            quit_on_destroy: Cell::new(false),
         });
         let win = Window(internal.clone());
         let rc = Rc::into_raw(internal);
@@ -97,9 +119,32 @@ impl Window {
         }
         Ok(win)
     }
-    pub(crate) fn add_nicon(&self, ni: NotifyIcon, id: u16) {
-        assert!(!self.0.nicons.borrow().contains_key(&id)); //TODO handling duplicate ids
-        self.0.nicons.borrow_mut().insert(id, ni);
+    // Replaces any icon that was dropped or removed, the builder having checked the id is free.
+    // Entries of other icons that are gone are dropped too, so the map doesn't keep growing in
+    // apps that create and drop icons all the time.
+    pub(crate) fn add_nicon(&self, ni: &NotifyIcon) {
+        let mut nicons = self.0.nicons.borrow_mut();
+        nicons.retain(|_, ni| ni.upgrade().is_some());
+        nicons.insert(ni.id(), ni.downgrade());
+    }
+    pub(crate) fn nicon(&self, id: u16) -> Option<NotifyIcon> {
+        let mut nicons = self.0.nicons.borrow_mut();
+        let ni = nicons.get(&id).and_then(|ni| ni.upgrade());
+        if ni.is_none() {
+            nicons.remove(&id);
+        }
+        ni
+    }
+    fn nicons(&self) -> Vec<NotifyIcon> {
+        let mut live = Vec::new();
+        self.0.nicons.borrow_mut().retain(|_, ni| match ni.upgrade() {
+            Some(ni) => {
+                live.push(ni);
+                true
+            },
+            None => false,
+        });
+        live
     }
     pub fn as_ref(&self) -> WindowRef {
         WindowRef {
@@ -109,6 +154,22 @@ impl Window {
     pub fn as_raw(&self) -> HWND {
         *self.0.hwnd
     }
+    pub fn set_dark_title_bar(&self, dark: bool) -> Result<(), Error> {
+        set_dark_title_bar(self.as_raw(), dark)
+    }
+    // Only has an effect on menus when the app mode is set to `AppMode::AllowDark`.
+    pub fn allow_dark_mode(&self, allow: bool) -> Result<(), Error> {
+        allow_dark_mode_for_window(self.as_raw(), allow)
+    }
+    pub(crate) fn set_quit_on_destroy(&self, quit: bool) {
+        self.0.quit_on_destroy.set(quit);
+    }
+    fn update_nicon_themes(&self) {
+        for ni in self.nicons() {
+            // Explorer may be restarting, in which case the icon will be re-added later anyway.
+            let _ = ni.update_theme();
+        }
+    }
     pub(crate) unsafe fn from_raw(hwnd: HWND) -> Result<Option<Window>, Error> {
         Error::clear();
         let raw = GetWindowLongPtrW(hwnd, 0) as *const WindowInternal;
@@ -127,31 +188,174 @@ impl Window {
         let hwnd = self.as_raw();
         let raw = GetWindowLongPtrW(hwnd, 0) as *const WindowInternal;
         assert!(!raw.is_null());
-        Rc::from_raw(raw);
-        //if is the root message window of the thread {
+        // Windows would destroy the menu bar itself right after this, so it has to be detached
+        // first to avoid destroying it twice. The bar itself goes with the window, as it may be
+        // in use by `with_menu_bar` right now.
+        if self.0.menu_bar.borrow().is_some() && SetMenu(hwnd, null_mut()) == 0 {
+            Error::get_last_error().die("Failed to detach menu bar");
+        }
+        accel::register(hwnd, None);
+        // Icons left behind would linger in the taskbar until the pointer passes over them.
+        for ni in self.nicons() {
+            let _ = ni.remove();
+        }
+        let internal = Rc::from_raw(raw);
+        if internal.quit_on_destroy.get() {
             PostQuitMessage(0);
-        //}
+        }
+    }
+    // Returns the menu bar that was previously attached to the window, if any. Fails with
+    // ERROR_BUSY from within `with_menu_bar`.
+    pub fn set_menu_bar(&self, bar: MenuBar) -> Result<Option<MenuBar>, Error> {
+        let old = {
+            let mut current = self.menu_bar_mut()?;
+            unsafe {
+                if SetMenu(self.as_raw(), bar.as_raw()) == 0 {
+                    return Err(Error::get_last_error());
+                }
+            }
+            current.replace(bar)
+        };
+        self.show_shortcuts()?;
+        self.draw_menu_bar()?;
+        Ok(old)
+    }
+    // Fails with ERROR_BUSY from within `with_menu_bar`.
+    pub fn take_menu_bar(&self) -> Result<Option<MenuBar>, Error> {
+        let mut current = self.menu_bar_mut()?;
+        if current.is_none() {
+            return Ok(None);
+        }
+        unsafe {
+            if SetMenu(self.as_raw(), null_mut()) == 0 {
+                return Err(Error::get_last_error());
+            }
+        }
+        Ok(current.take())
+    }
+    fn menu_bar_mut<'a>(&'a self) -> Result<RefMut<'a, Option<MenuBar>>, Error> {
+        self.0.menu_bar.try_borrow_mut().map_err(|_| Error::from_raw(ERROR_BUSY))
     }
-    pub(crate) fn set_menu_handler(&self, func: Box<FnMut(u16, &Window)>) {
-        let _old = self.0.menu_handler.replace(Some(func));
+    // Gives access to the attached menu bar, refreshing the shortcuts and redrawing it afterwards
+    // to reflect any changes. The bar can't be replaced or taken from within the closure.
+    pub fn with_menu_bar<T, R>(
+        &self, func: T,
+    ) -> Result<Option<R>, Error> where T: FnOnce(&MenuBar) -> R {
+        let ret = match *self.0.menu_bar.borrow() {
+            Some(ref bar) => func(bar),
+            None => return Ok(None),
+        };
+        self.show_shortcuts()?;
+        self.draw_menu_bar()?;
+        Ok(Some(ret))
+    }
+    // Applies the accelerators to all messages for this window and its children. The shortcuts are
+    // shown beside the menu bar items and popup menu items with the same command ids. Returns the
+    // table that was previously in use, if any.
+    pub fn set_accelerators(
+        &self, table: AcceleratorTable,
+    ) -> Result<Option<AcceleratorTable>, Error> {
+        accel::register(self.as_raw(), Some(&table));
+        let old = self.0.accelerators.borrow_mut().take();
+        *self.0.accelerators.borrow_mut() = Some(table);
+        self.show_shortcuts()?;
+        self.draw_menu_bar()?;
+        Ok(old)
+    }
+    pub fn take_accelerators(&self) -> Option<AcceleratorTable> {
+        accel::register(self.as_raw(), None);
+        self.0.accelerators.borrow_mut().take()
+    }
+    fn show_shortcuts(&self) -> Result<(), Error> {
+        match *self.0.menu_bar.borrow() {
+            Some(ref bar) => self.show_shortcuts_in(bar),
+            None => Ok(()),
+        }
+    }
+    pub(crate) fn show_shortcuts_in(&self, menu: &Menu) -> Result<(), Error> {
+        match *self.0.accelerators.borrow() {
+            Some(ref table) => menu.show_shortcuts(table),
+            None => Ok(()),
+        }
+    }
+    fn draw_menu_bar(&self) -> Result<(), Error> {
+        if self.0.menu_bar.borrow().is_none() {
+            return Ok(());
+        }
+        if unsafe { DrawMenuBar(self.as_raw()) } == 0 {
+            return Err(Error::get_last_error());
+        }
+        Ok(())
     }
     pub(crate) fn handle_event(
         &self, msg: UINT, wparam: WPARAM, lparam: LPARAM,
     ) -> Option<EventResponse> {
         let event = unsafe { Event::from_raw(msg, wparam, lparam) };
+        #[cfg(feature = "trace")]
+        let trace = Trace::begin(self.as_raw(), msg, &event);
+        let (_route, response) = self.dispatch(event);
+        #[cfg(feature = "trace")]
+        trace.end(_route, &response);
+        response
+    }
+    // Handles the command as if it came from the menu bar.
+    pub(crate) fn send_command(&self, id: u16) -> Option<EventResponse> {
+        self.dispatch(Event::MenuCommand(id)).1
+    }
+    fn dispatch(&self, event: Event) -> (Route, Option<EventResponse>) {
         match event {
+            // Popup menus return their commands directly, so anything else comes from the menu bar
+            // or an accelerator.
             Event::MenuCommand(id) => {
-                (self.0.menu_handler.take().expect("Received menu command without menu active!?"))(id, self);
-                None
+                if command::invoke(id, self) {
+                    return (Route::Menu, None);
+                }
+                (Route::Window, (self.0.handler)(event, self))
+            },
+            // Owner drawn menu items are handled here, other owner drawn controls go to the window.
+            Event::MeasureItem(mis) => match unsafe { ownerdraw::measure(mis, self) } {
+                Some(response) => (Route::Menu, Some(response)),
+                None => (Route::Window, (self.0.handler)(event, self)),
+            },
+            Event::DrawItem(dis) => match unsafe { ownerdraw::draw(dis, self) } {
+                Some(response) => (Route::Menu, Some(response)),
+                None => (Route::Window, (self.0.handler)(event, self)),
+            },
+            // Lazy submenus are filled first, so the window can still adjust them afterwards.
+            Event::InitMenuPopup(menu) => {
+                populate_lazy(menu, self);
+                (Route::Window, (self.0.handler)(event, self))
             },
             Event::Destroy => unsafe {
                 self.destroy();
-                None
+                (Route::Destroy, None)
             },
+            // The handler is free to remove the icon, so it must not be borrowed from the map.
             Event::NotifyIcon(id, e) => {
-                self.0.nicons.borrow()[&id].handle_event(e, self)
+                (Route::NotifyIcon, self.nicon(id).and_then(|ni| ni.handle_event(e, self)))
+            },
+            Event::Timer(timer) => match animated_icon(timer) {
+                Some(id) => {
+                    if let Some(ni) = self.nicon(id) {
+                        // Explorer may be restarting, in which case the next frame will do.
+                        let _ = ni.next_frame();
+                    }
+                    (Route::NotifyIcon, None)
+                },
+                None => (Route::Window, (self.0.handler)(event, self)),
+            },
+            // Explorer restarted and forgot about every icon.
+            Event::TaskbarCreated => {
+                for ni in self.nicons() {
+                    let _ = ni.restore();
+                }
+                (Route::Window, (self.0.handler)(event, self))
+            },
+            Event::SettingChange(Some(ref area)) if area == COLOR_SET_AREA => {
+                self.update_nicon_themes();
+                (Route::Window, (self.0.handler)(event, self))
             },
-            _ => (self.0.handler)(event, self),
+            _ => (Route::Window, (self.0.handler)(event, self)),
         }
     }
 }
@@ -179,6 +383,37 @@ impl WindowBuilder {
     pub fn create_child(self, _window: WindowRef) -> Result<WindowRef, Error> {
         unimplemented!()
     }
+    // Creates a hidden, borderless window that stays on top and out of the taskbar, on the current
+    // thread rather than a new one. The thread must already be running a message loop, which the
+    // window leaves running when it is destroyed.
+    pub fn create_popup(self, rect: Rect) -> Result<WindowRef, Error> {
+        self.create_local(rect).map(|window| window.as_ref())
+    }
+    pub(crate) fn create_local(self, rect: Rect) -> Result<Window, Error> {
+        let class = self.class.expect("Must specify a class");
+        let handler = self.handler.unwrap_or_else(|| Box::new(|_, _| None));
+        WINDOW_HANDLER.with(|x| x.set(Some(handler)));
+        let hwnd = unsafe { CreateWindowExW(
+            WS_EX_TOOLWINDOW | WS_EX_TOPMOST, class.as_wstr(),
+            null_mut(),
+            WS_POPUP,
+            rect.left, rect.top,
+            rect.width(), rect.height(),
+            null_mut(), null_mut(), null_mut(), null_mut(),
+        )};
+        if hwnd.is_null() {
+            // The handler is still waiting if the window procedure never ran.
+            WINDOW_HANDLER.with(|x| x.set(None));
+            return Err(Error::get_last_error());
+        }
+        let window = unsafe { Window::from_raw(hwnd)?.expect("Window was not initialized") };
+        window.0.class.set(Some(class));
+        Ok(window)
+    }
+    // A window that is never shown, with its own thread to receive messages on. It is a hidden top
+    // level window rather than a message only one, as those don't get broadcasts such as
+    // TaskbarCreated and WM_SETTINGCHANGE, which notify icons need to follow Explorer restarts and
+    // theme changes.
     pub fn create_message(self) -> Result<WindowRef, Error> {
         let class = self.class.expect("Must specify a class");
         let handler = self.handler.unwrap_or_else(|| Box::new(|_, _| None));
@@ -193,7 +428,7 @@ impl WindowBuilder {
                 0,
                 CW_USEDEFAULT, CW_USEDEFAULT,
                 0, 0,
-                HWND_MESSAGE, null_mut(), null_mut(), null_mut(),
+                null_mut(), null_mut(), null_mut(), null_mut(),
             )};
             if hwnd.is_null() {
                 let err = Error::get_last_error();
@@ -203,6 +438,7 @@ impl WindowBuilder {
             }
             let window = unsafe { Window::from_raw(hwnd).unwrap().unwrap() };
             window.0.class.set(Some(class));
+            window.0.quit_on_destroy.set(true);
             let remote = window.as_ref();
             *rpair.0.lock().unwrap() = Some(Ok(remote));
             rpair.1.notify_one();
@@ -5,6 +5,7 @@ use winapi::shared::minwindef::{LPARAM, LRESULT, UINT, WPARAM};
 use winapi::shared::windef::HWND;
 use winapi::um::winuser::{DefWindowProcW, DispatchMessageW, GetMessageW, MSG, TranslateMessage};
 use Error;
+use accel;
 use window::Window;
 
 pub(crate) unsafe extern "system" fn wndproc(
@@ -26,6 +27,9 @@ pub(crate) fn message_loop() {
         } else if ret == -1 {
             Error::get_last_error().die("Failed to get message");
         }
+        if accel::translate(&msg) {
+            continue;
+        }
         unsafe { TranslateMessage(&msg) };
         unsafe { DispatchMessageW(&msg) };
     }
@@ -0,0 +1,64 @@
+#!/usr/bin/env python3
+# Writes the icon files used by the tests in src/ico.rs. Run from this directory.
+import struct
+import zlib
+
+
+def bmp(width, height, bit_count):
+    # A DIB as stored in icon files: the header, a palette when there is one, the color rows and
+    # the 1 bit AND mask, with the height covering both.
+    header = struct.pack('<IiiHHIIiiII', 40, width, height * 2, 1, bit_count, 0, 0, 0, 0, 0, 0)
+    palette = b'\0' * (4 << bit_count) if bit_count <= 8 else b''
+    stride = (width * bit_count + 31) // 32 * 4
+    mask_stride = (width + 31) // 32 * 4
+    return header + palette + b'\x80' * stride * height + b'\0' * mask_stride * height
+
+
+def png(width, height):
+    def chunk(kind, data):
+        crc = zlib.crc32(kind + data)
+        return struct.pack('>I', len(data)) + kind + data + struct.pack('>I', crc)
+    ihdr = struct.pack('>IIBBBBB', width, height, 8, 6, 0, 0, 0)
+    rows = (b'\0' + b'\x80' * width * 4) * height
+    return (b'\x89PNG\r\n\x1a\n' + chunk(b'IHDR', ihdr) + chunk(b'IDAT', zlib.compress(rows, 9))
+        + chunk(b'IEND', b''))
+
+
+def ico(images, kind=1, count=None, offsets=None):
+    count = len(images) if count is None else count
+    out = struct.pack('<HHH', 0, kind, count)
+    offset = 6 + 16 * len(images)
+    for i, (width, height, bit_count, data) in enumerate(images):
+        at = offset if offsets is None else offsets[i]
+        # The directory stores 256 as 0.
+        out += struct.pack('<BBBBHHII', width % 256, height % 256, 0, 0, 1, bit_count,
+            len(data), at)
+        offset += len(data)
+    return out + b''.join(data for _, _, _, data in images)
+
+
+def entry(width, height, bit_count, data):
+    return (width, height, bit_count, data)
+
+
+def write(name, data):
+    with open(name, 'wb') as f:
+        f.write(data)
+
+
+write('bmp.ico', ico([
+    entry(16, 16, 32, bmp(16, 16, 32)),
+    entry(32, 32, 8, bmp(32, 32, 8)),
+    entry(32, 32, 32, bmp(32, 32, 32)),
+    entry(48, 48, 4, bmp(48, 48, 4)),
+]))
+write('png.ico', ico([
+    entry(16, 16, 32, bmp(16, 16, 32)),
+    entry(256, 256, 32, png(256, 256)),
+]))
+write('truncated.ico', ico([entry(16, 16, 32, bmp(16, 16, 32))], count=3)[:6 + 16 * 2])
+write('bad_offset.ico', ico([
+    entry(16, 16, 32, bmp(16, 16, 32)),
+    entry(32, 32, 32, bmp(32, 32, 32)),
+], offsets=[38, 100000]))
+write('cursor.cur', ico([entry(32, 32, 32, bmp(32, 32, 32))], kind=2))